@@ -0,0 +1,563 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, io::{self, BufReader, BufWriter, Read, Write}, path::PathBuf, sync::Arc};
+
+use anyhow::bail;
+use base64::{Engine, engine::general_purpose};
+use rusqlite::params;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::fs;
+use uuid::Uuid;
+
+use super::{
+  BlobPadMetadata, BlobOptions, DatabasePool, EncryptedPad, Job, PadMap, QuarantinedPad, WrittenBlob, RECOVERED_PAD_ID,
+  crypto::{self, KEY_SIZE, SALT_SIZE}, encrypt_to_temp_file, store_blob_content,
+  jobs::JobReader,
+  utils::{chunk_size_for_file, is_compressed_media_type, now_millis}
+};
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"CPADARCH";
+const ARCHIVE_VERSION: u16 = 1;
+const ARCHIVE_CHUNK_SIZE: usize = 1024 * 1024; // Plaintext bytes of a blob stored in each frame
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024; // Frames larger than this are treated as corrupt rather than allocated
+
+// Every frame is encrypted separately and starts with its sequence number, so frames cannot be reordered or dropped
+const FRAME_MANIFEST: u8 = 0;
+const FRAME_PAD: u8 = 1;
+const FRAME_BLOB_CHUNK: u8 = 2;
+const FRAME_END: u8 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveManifest {
+  version: u16,
+  #[serde(rename = "createdAt")]
+  created_at: u64,
+  #[serde(rename = "padCount")]
+  pad_count: usize
+}
+
+// Blob pads are followed by size bytes of blob chunk frames
+#[derive(Serialize, Deserialize)]
+struct ArchivePad {
+  id: Uuid,
+  #[serde(rename = "parentId")]
+  parent_id: Option<Uuid>,
+  metadata: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  data: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  size: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  thumbnail: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct ArchiveReport {
+  #[serde(rename = "createdAt")]
+  created_at: u64,
+  pads: usize,
+  #[serde(rename = "rootIds")]
+  root_ids: Vec<Uuid>,
+  // Pads that could not be decrypted and so are missing from the archive
+  #[serde(rename = "quarantinedPads")]
+  quarantined_pads: Vec<Uuid>
+}
+
+struct ArchiveWriter<W: Write> {
+  inner: W,
+  key: [u8; KEY_SIZE],
+  sequence: u64
+}
+
+impl<W: Write> ArchiveWriter<W> {
+  fn new(mut inner: W, passphrase: &str) -> Result<Self, anyhow::Error> {
+    let salt = crypto::generate_salt()?;
+    let mut key = [0u8; KEY_SIZE];
+    crypto::derive_key(passphrase.as_bytes(), &salt, &mut key)?;
+    inner.write_all(ARCHIVE_MAGIC)?;
+    inner.write_all(&ARCHIVE_VERSION.to_be_bytes())?;
+    inner.write_all(&salt)?;
+    Ok(Self {
+      inner,
+      key,
+      sequence: 0
+    })
+  }
+
+  fn write_frame(&mut self, kind: u8, payload: &[u8]) -> Result<(), anyhow::Error> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend(self.sequence.to_be_bytes());
+    frame.push(kind);
+    frame.extend_from_slice(payload);
+    let encrypted_frame = crypto::encrypt(&frame, &self.key)?;
+    self.inner.write_all(&(encrypted_frame.len() as u32).to_be_bytes())?;
+    self.inner.write_all(&encrypted_frame)?;
+    self.sequence += 1;
+    Ok(())
+  }
+
+  fn write_json<T: Serialize>(&mut self, kind: u8, value: &T) -> Result<(), anyhow::Error> {
+    self.write_frame(kind, serde_json::to_string(value)?.as_bytes())
+  }
+
+  fn write_blob<R: Read>(&mut self, reader: &mut R) -> Result<(), anyhow::Error> {
+    let mut buffer = vec![0u8; ARCHIVE_CHUNK_SIZE];
+    loop {
+      let mut filled = 0;
+      while filled < buffer.len() {
+        let bytes_read = reader.read(&mut buffer[filled..])?;
+        if bytes_read == 0 {
+          break;
+        }
+        filled += bytes_read;
+      }
+      if filled == 0 {
+        return Ok(());
+      }
+      self.write_frame(FRAME_BLOB_CHUNK, &buffer[..filled])?;
+    }
+  }
+
+  fn finish(mut self) -> Result<W, anyhow::Error> {
+    self.write_frame(FRAME_END, &[])?;
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+}
+
+struct ArchiveReader<R: Read> {
+  inner: R,
+  key: [u8; KEY_SIZE],
+  sequence: u64
+}
+
+impl<R: Read> ArchiveReader<R> {
+  fn new(mut inner: R, passphrase: &str) -> Result<Self, anyhow::Error> {
+    let mut magic = [0u8; 8];
+    inner.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+      bail!("Not a Cipherpad archive")
+    }
+    let mut version = [0u8; 2];
+    inner.read_exact(&mut version)?;
+    let version = u16::from_be_bytes(version);
+    if version != ARCHIVE_VERSION {
+      bail!("Unsupported archive version {}", version)
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    inner.read_exact(&mut salt)?;
+    let mut key = [0u8; KEY_SIZE];
+    crypto::derive_key(passphrase.as_bytes(), &salt, &mut key)?;
+    Ok(Self {
+      inner,
+      key,
+      sequence: 0
+    })
+  }
+
+  fn read_frame(&mut self) -> Result<(u8, Vec<u8>), anyhow::Error> {
+    let mut frame_len = [0u8; 4];
+    self.inner.read_exact(&mut frame_len)?;
+    let frame_len = u32::from_be_bytes(frame_len) as usize;
+    if frame_len > MAX_FRAME_SIZE {
+      bail!("Archive is corrupt")
+    }
+    let mut encrypted_frame = vec![0u8; frame_len];
+    self.inner.read_exact(&mut encrypted_frame)?;
+    let frame = match crypto::decrypt(&encrypted_frame, &self.key) {
+      Ok(frame) => frame,
+      Err(_) if self.sequence == 0 => bail!("Wrong passphrase or corrupt archive"),
+      Err(_) => bail!("Archive is corrupt")
+    };
+    if frame.len() < 9 || frame[..8] != self.sequence.to_be_bytes() {
+      bail!("Archive frames are out of order")
+    }
+    self.sequence += 1;
+    Ok((frame[8], frame[9..].to_vec()))
+  }
+
+  fn read_json<T: for<'de> Deserialize<'de>>(&mut self, kind: u8) -> Result<T, anyhow::Error> {
+    let (frame_kind, payload) = self.read_frame()?;
+    if frame_kind != kind {
+      bail!("Unexpected record in archive")
+    }
+    Ok(serde_json::from_slice(&payload)?)
+  }
+}
+
+// Reads the blob chunk frames that follow a blob pad as one stream
+struct ArchiveBlobReader<'a, R: Read> {
+  archive_reader: &'a mut ArchiveReader<R>,
+  remaining: u64,
+  chunk: Vec<u8>,
+  position: usize
+}
+
+impl<'a, R: Read> Read for ArchiveBlobReader<'a, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.position == self.chunk.len() {
+      if self.remaining == 0 {
+        return Ok(0);
+      }
+      let (kind, chunk) = self.archive_reader.read_frame()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+      if kind != FRAME_BLOB_CHUNK || chunk.is_empty() || chunk.len() as u64 > self.remaining {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Archive blob is corrupt"));
+      }
+      self.remaining -= chunk.len() as u64;
+      self.chunk = chunk;
+      self.position = 0;
+    }
+    let bytes_read = buf.len().min(self.chunk.len() - self.position);
+    buf[..bytes_read].copy_from_slice(&self.chunk[self.position..self.position + bytes_read]);
+    self.position += bytes_read;
+    Ok(bytes_read)
+  }
+}
+
+// Orders pads so every parent comes before its children, starting from root_id or from every top level pad.
+// Pads are returned with their parent id in the archive, which is None for the pads at the top of it.
+fn archive_order(pad_map: &PadMap, root_id: Option<Uuid>) -> Result<Vec<(EncryptedPad, Option<Uuid>)>, anyhow::Error> {
+  let mut children: HashMap<Option<Uuid>, Vec<&EncryptedPad>> = HashMap::new();
+  for encrypted_pad in pad_map.pads.values().filter(|encrypted_pad| encrypted_pad.id != RECOVERED_PAD_ID) {
+    let parent_id = encrypted_pad.parent_id.filter(|parent_id| *parent_id != RECOVERED_PAD_ID && pad_map.pads.contains_key(parent_id));
+    children.entry(parent_id).or_default().push(encrypted_pad);
+  }
+  let mut queue: VecDeque<(&EncryptedPad, Option<Uuid>)> = match root_id {
+    Some(root_id) => match pad_map.pads.get(&root_id) {
+      Some(root) => VecDeque::from([(root, None)]),
+      None => bail!("No pad with that id")
+    },
+    None => children.remove(&None).unwrap_or_default().into_iter().map(|encrypted_pad| (encrypted_pad, None)).collect()
+  };
+  let mut ordered_pads = Vec::new();
+  while let Some((encrypted_pad, parent_id)) = queue.pop_front() {
+    queue.extend(children.remove(&Some(encrypted_pad.id)).unwrap_or_default().into_iter().map(|child| (child, Some(encrypted_pad.id))));
+    ordered_pads.push((encrypted_pad.clone(), parent_id));
+  }
+  Ok(ordered_pads)
+}
+
+async fn write_archive(pool: &DatabasePool, master_key: &[u8], ordered_pads: &[(EncryptedPad, Option<Uuid>)], manifest: &ArchiveManifest, file: &str, passphrase: &str, job: Arc<Job>) -> Result<(), anyhow::Error> {
+  let mut archive_writer = ArchiveWriter::new(BufWriter::new(std::fs::File::create(file)?), passphrase)?;
+  archive_writer.write_json(FRAME_MANIFEST, manifest)?;
+
+  for (encrypted_pad, parent_id) in ordered_pads {
+    job.check_cancelled()?;
+    let metadata: Value = serde_json::from_str(&encrypted_pad.metadata)?;
+    let mut archive_pad = ArchivePad {
+      id: encrypted_pad.id,
+      parent_id: *parent_id,
+      metadata: encrypted_pad.metadata.clone(),
+      data: None,
+      size: None,
+      thumbnail: None
+    };
+    if metadata.get("type").and_then(Value::as_str) == Some("blob") {
+      archive_pad.thumbnail = encrypted_pad.clone().get_thumbnail(pool, master_key).await?
+        .map(|thumbnail| general_purpose::STANDARD.encode(thumbnail));
+      let name = metadata.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+      job.set_current_item(name);
+      let reader_job = job.clone();
+      archive_writer = encrypted_pad.clone().read_pad_data(pool, master_key, move |pad_reader| {
+        let total_bytes = pad_reader.len();
+        archive_pad.size = Some(total_bytes);
+        archive_writer.write_json(FRAME_PAD, &archive_pad)?;
+        archive_writer.write_blob(&mut JobReader::new(pad_reader, reader_job, total_bytes))?;
+        Ok(archive_writer)
+      }).await?;
+    } else {
+      archive_pad.data = Some(encrypted_pad.clone().decrypt_pad_data(master_key, pool).await?);
+      archive_writer.write_json(FRAME_PAD, &archive_pad)?;
+    }
+  }
+  archive_writer.finish()?;
+  Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn export_archive(pool: &DatabasePool, master_key: &[u8], pad_map: PadMap, quarantined_pads: &[QuarantinedPad], root_id: Option<Uuid>, file: &str, passphrase: &str, job: Arc<Job>) -> Result<ArchiveReport, anyhow::Error> {
+  let ordered_pads = archive_order(&pad_map, root_id)?;
+  let manifest = ArchiveManifest {
+    version: ARCHIVE_VERSION,
+    created_at: now_millis(),
+    pad_count: ordered_pads.len()
+  };
+  if let Err(err) = write_archive(pool, master_key, &ordered_pads, &manifest, file, passphrase, job).await {
+    let _ = fs::remove_file(file).await;
+    return Err(err);
+  }
+  let exported_ids: HashSet<Uuid> = ordered_pads.iter().map(|(encrypted_pad, _)| encrypted_pad.id).collect();
+  Ok(ArchiveReport {
+    created_at: manifest.created_at,
+    pads: ordered_pads.len(),
+    root_ids: ordered_pads.iter()
+      .filter(|(_, parent_id)| parent_id.is_none())
+      .map(|(encrypted_pad, _)| encrypted_pad.id)
+      .collect(),
+    quarantined_pads: quarantined_pads.iter()
+      .filter(|quarantined_pad| root_id.is_none() || quarantined_pad.parent_id.is_some_and(|parent_id| exported_ids.contains(&parent_id)))
+      .map(|quarantined_pad| quarantined_pad.id)
+      .collect()
+  })
+}
+
+enum ImportedPad {
+  Text {
+    id: Uuid,
+    parent_id: Option<Uuid>,
+    encrypted_metadata: Vec<u8>,
+    encrypted_data: Vec<u8>
+  },
+  Blob {
+    id: Uuid,
+    parent_id: Option<Uuid>,
+    encrypted_metadata: Vec<u8>,
+    encrypted_thumbnail: Option<Vec<u8>>,
+    written_blob: WrittenBlob,
+    temp_path: PathBuf
+  }
+}
+
+// Temp files are added to temp_paths as soon as they are written, so they are removed even if a later pad fails
+#[derive(Default)]
+struct ImportedPads {
+  pads: Vec<ImportedPad>,
+  temp_paths: Vec<PathBuf>
+}
+
+async fn read_archive(master_key: &[u8], file: &str, passphrase: &str, parent_id: Option<Uuid>, preserve_ids: bool, job: Arc<Job>, imported: &mut ImportedPads) -> Result<(ArchiveManifest, Vec<Uuid>), anyhow::Error> {
+  let mut archive_reader = ArchiveReader::new(BufReader::new(std::fs::File::open(file)?), passphrase)?;
+  let manifest: ArchiveManifest = archive_reader.read_json(FRAME_MANIFEST)?;
+  if manifest.version != ARCHIVE_VERSION {
+    bail!("Unsupported archive version {}", manifest.version)
+  }
+  let mut ids = HashMap::new();
+  let mut root_ids = Vec::new();
+
+  loop {
+    job.check_cancelled()?;
+    let (kind, payload) = archive_reader.read_frame()?;
+    match kind {
+      FRAME_PAD => {},
+      FRAME_END => break,
+      _ => bail!("Unexpected record in archive")
+    }
+    let archive_pad: ArchivePad = serde_json::from_slice(&payload)?;
+    let id = if preserve_ids { archive_pad.id } else { Uuid::new_v4() };
+    ids.insert(archive_pad.id, id);
+    let pad_parent_id = match archive_pad.parent_id.and_then(|archive_parent_id| ids.get(&archive_parent_id)) {
+      Some(pad_parent_id) => Some(*pad_parent_id),
+      None => {
+        root_ids.push(id);
+        parent_id
+      }
+    };
+
+    match (archive_pad.data, archive_pad.size) {
+      (Some(data), _) => imported.pads.push(ImportedPad::Text {
+        id,
+        parent_id: pad_parent_id,
+        encrypted_metadata: crypto::encrypt(archive_pad.metadata.as_bytes(), master_key)?,
+        encrypted_data: crypto::encrypt_compressed(data.as_bytes(), master_key)?
+      }),
+      (None, Some(size)) => {
+        let mut blob_pad_metadata: BlobPadMetadata = serde_json::from_str(&archive_pad.metadata)?;
+        let options = BlobOptions {
+          chunk_size: chunk_size_for_file(size),
+          compress: !is_compressed_media_type(blob_pad_metadata.media_type.as_deref().unwrap_or_default())
+        };
+        job.set_current_item(blob_pad_metadata.name.clone());
+        let reader_job = job.clone();
+        let (temp_path, written_blob, returned_reader) = encrypt_to_temp_file(master_key, options, job.clone(), move |pad_writer| {
+          let blob_reader = ArchiveBlobReader {
+            archive_reader: &mut archive_reader,
+            remaining: size,
+            chunk: Vec::new(),
+            position: 0
+          };
+          io::copy(&mut JobReader::new(blob_reader, reader_job, size), pad_writer)?;
+          Ok(archive_reader)
+        }).await?;
+        archive_reader = returned_reader;
+        imported.temp_paths.push(temp_path.clone());
+        blob_pad_metadata.record_written_blob(&written_blob);
+        let encrypted_thumbnail = match archive_pad.thumbnail {
+          Some(thumbnail) => Some(crypto::encrypt(&general_purpose::STANDARD.decode(thumbnail)?, master_key)?),
+          None => None
+        };
+        imported.pads.push(ImportedPad::Blob {
+          id,
+          parent_id: pad_parent_id,
+          encrypted_metadata: crypto::encrypt(serde_json::to_string(&blob_pad_metadata)?.as_bytes(), master_key)?,
+          encrypted_thumbnail,
+          written_blob,
+          temp_path
+        });
+      },
+      (None, None) => bail!("Archive pad {} has no data", archive_pad.id)
+    }
+  }
+
+  if imported.pads.len() != manifest.pad_count {
+    bail!("Archive lists {} pads but contains {}", manifest.pad_count, imported.pads.len())
+  }
+  Ok((manifest, root_ids))
+}
+
+// Archive pads are inserted in archive order, which puts every parent before its children
+async fn save_archive_pads(pool: &DatabasePool, imported_pads: Vec<ImportedPad>, job: Arc<Job>) -> Result<(), anyhow::Error> {
+  pool.transaction(move |transaction| {
+    for imported_pad in imported_pads {
+      let id = match &imported_pad {
+        ImportedPad::Text { id, .. } | ImportedPad::Blob { id, .. } => *id
+      };
+      let exists = transaction.query_row(
+        "SELECT EXISTS(SELECT 1 FROM node WHERE id = ?1)",
+        params![id],
+        |row| row.get::<usize, bool>(0)
+      )?;
+      if exists {
+        bail!("Pad {} already exists in this vault", id)
+      }
+      match imported_pad {
+        ImportedPad::Text { id, parent_id, encrypted_metadata, encrypted_data } => {
+          transaction.execute(
+            "INSERT INTO node (id, parent_id, pad_metadata, pad_data) VALUES (?1, ?2, ?3, ?4)",
+            params![id, parent_id, encrypted_metadata, encrypted_data]
+          )?;
+        },
+        ImportedPad::Blob { id, parent_id, encrypted_metadata, encrypted_thumbnail, written_blob, temp_path } => {
+          store_blob_content(transaction, &written_blob, &temp_path, &job)?;
+          transaction.execute(
+            "INSERT INTO node (id, parent_id, pad_metadata, pad_data, content_hash, thumbnail) VALUES (?1, ?2, ?3, ZEROBLOB(0), ?4, ?5)",
+            params![id, parent_id, encrypted_metadata, written_blob.content_hash, encrypted_thumbnail]
+          )?;
+        }
+      }
+    }
+    Ok(())
+  }).await
+}
+
+pub async fn import_archive(pool: &DatabasePool, master_key: &[u8], file: &str, passphrase: &str, parent_id: Option<Uuid>, preserve_ids: bool, job: Arc<Job>) -> Result<ArchiveReport, anyhow::Error> {
+  let mut imported = ImportedPads::default();
+  let read_result = read_archive(master_key, file, passphrase, parent_id, preserve_ids, job.clone(), &mut imported).await;
+  let pads = imported.pads.len();
+  let save_result = match read_result {
+    Ok(archive_contents) => save_archive_pads(pool, imported.pads, job).await.map(|_| archive_contents),
+    Err(err) => Err(err)
+  };
+  for temp_path in imported.temp_paths {
+    fs::remove_file(&temp_path).await?;
+  }
+  let (manifest, root_ids) = save_result?;
+  Ok(ArchiveReport {
+    created_at: manifest.created_at,
+    pads,
+    root_ids,
+    quarantined_pads: Vec::new()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  const PASSPHRASE: &str = "correct horse battery staple";
+  const HEADER_SIZE: usize = ARCHIVE_MAGIC.len() + 2 + SALT_SIZE;
+
+  // A manifest and one blob pad whose data spans several chunk frames
+  fn write_sample_archive(data: &[u8]) -> Vec<u8> {
+    let mut archive_writer = ArchiveWriter::new(Vec::new(), PASSPHRASE).unwrap();
+    archive_writer.write_json(FRAME_MANIFEST, &ArchiveManifest { version: ARCHIVE_VERSION, created_at: 1, pad_count: 1 }).unwrap();
+    let archive_pad = ArchivePad {
+      id: Uuid::new_v4(),
+      parent_id: None,
+      metadata: "{}".to_string(),
+      data: None,
+      size: Some(data.len() as u64),
+      thumbnail: None
+    };
+    archive_writer.write_json(FRAME_PAD, &archive_pad).unwrap();
+    archive_writer.write_blob(&mut &data[..]).unwrap();
+    archive_writer.finish().unwrap()
+  }
+
+  fn read_sample_archive(archive: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut archive_reader = ArchiveReader::new(Cursor::new(archive), passphrase)?;
+    let manifest: ArchiveManifest = archive_reader.read_json(FRAME_MANIFEST)?;
+    assert_eq!(manifest.pad_count, 1);
+    let archive_pad: ArchivePad = archive_reader.read_json(FRAME_PAD)?;
+    let mut data = Vec::new();
+    ArchiveBlobReader {
+      archive_reader: &mut archive_reader,
+      remaining: archive_pad.size.unwrap_or_default(),
+      chunk: Vec::new(),
+      position: 0
+    }.read_to_end(&mut data)?;
+    if archive_reader.read_frame()?.0 != FRAME_END {
+      bail!("Missing end of archive")
+    }
+    Ok(data)
+  }
+
+  fn sample_data() -> Vec<u8> {
+    (0..ARCHIVE_CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect()
+  }
+
+  // Start of each frame's length prefix
+  fn frame_offsets(archive: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = HEADER_SIZE;
+    while offset < archive.len() {
+      offsets.push(offset);
+      offset += 4 + u32::from_be_bytes(archive[offset..offset + 4].try_into().unwrap()) as usize;
+    }
+    offsets
+  }
+
+  #[test]
+  fn round_trip() {
+    let data = sample_data();
+    let archive = write_sample_archive(&data);
+    // Manifest, pad, three blob chunks and the end frame
+    assert_eq!(frame_offsets(&archive).len(), 6);
+    assert_eq!(read_sample_archive(archive, PASSPHRASE).unwrap(), data);
+  }
+
+  #[test]
+  fn wrong_passphrase() {
+    let archive = write_sample_archive(b"data");
+    let err = read_sample_archive(archive, "wrong passphrase").unwrap_err();
+    assert_eq!(err.to_string(), "Wrong passphrase or corrupt archive");
+  }
+
+  #[test]
+  fn truncation_is_detected() {
+    let archive = write_sample_archive(&sample_data());
+    let offsets = frame_offsets(&archive);
+    // Without the end frame, and cut off partway through a blob chunk
+    assert!(read_sample_archive(archive[..offsets[5]].to_vec(), PASSPHRASE).is_err());
+    assert!(read_sample_archive(archive[..offsets[3] + 100].to_vec(), PASSPHRASE).is_err());
+  }
+
+  #[test]
+  fn tampering_is_detected() {
+    let mut archive = write_sample_archive(&sample_data());
+    let offsets = frame_offsets(&archive);
+    archive[offsets[3] + 100] ^= 1;
+    let err = read_sample_archive(archive, PASSPHRASE).unwrap_err();
+    assert!(err.to_string().contains("Archive is corrupt"), "{}", err);
+  }
+
+  #[test]
+  fn dropped_frames_are_detected() {
+    let mut archive = write_sample_archive(&sample_data());
+    let offsets = frame_offsets(&archive);
+    archive.drain(offsets[3]..offsets[4]);
+    let err = read_sample_archive(archive, PASSPHRASE).unwrap_err();
+    assert!(err.to_string().contains("Archive frames are out of order"), "{}", err);
+  }
+}
\ No newline at end of file
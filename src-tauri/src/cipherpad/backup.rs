@@ -0,0 +1,132 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{Datelike, Local, NaiveDateTime};
+use serde::Serialize;
+use tokio::{fs, sync::Mutex};
+
+use super::{Cipherpad, DatabasePool, VaultSettings};
+
+const BACKUP_FILE_FORMAT: &str = "vault-%Y%m%d-%H%M.db";
+const PARTIAL_BACKUP_EXTENSION: &str = "partial"; // Renamed into place once complete, so a crash never leaves a truncated backup
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Serialize)]
+pub struct BackupReport {
+  path: String,
+  #[serde(rename = "removedBackups")]
+  removed_backups: Vec<String>
+}
+
+// Newest first
+async fn list_backups(directory: &Path) -> Result<Vec<(NaiveDateTime, PathBuf)>, anyhow::Error> {
+  let mut backups = Vec::new();
+  let mut entries = fs::read_dir(directory).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    if !entry.file_type().await?.is_file() {
+      continue;
+    }
+    let file_name = entry.file_name();
+    if let Some(created_at) = file_name.to_str()
+      .and_then(|file_name| NaiveDateTime::parse_from_str(file_name, BACKUP_FILE_FORMAT).ok()) {
+      backups.push((created_at, entry.path()));
+    }
+  }
+  backups.sort_by(|a, b| b.0.cmp(&a.0));
+  Ok(backups)
+}
+
+// Keeps the newest backup of each of the last keep_daily days and keep_weekly ISO weeks, plus the newest backup overall
+fn backups_to_remove(backups: &[(NaiveDateTime, PathBuf)], keep_daily: u32, keep_weekly: u32) -> Vec<PathBuf> {
+  let mut kept_days = HashSet::new();
+  let mut kept_weeks = HashSet::new();
+  let mut to_remove = Vec::new();
+
+  for (index, (created_at, path)) in backups.iter().enumerate() {
+    let day = created_at.date();
+    let week = (created_at.iso_week().year(), created_at.iso_week().week());
+    let mut keep = index == 0;
+    if !kept_days.contains(&day) && kept_days.len() < keep_daily as usize {
+      kept_days.insert(day);
+      keep = true;
+    }
+    if !kept_weeks.contains(&week) && kept_weeks.len() < keep_weekly as usize {
+      kept_weeks.insert(week);
+      keep = true;
+    }
+    if !keep {
+      to_remove.push(path.clone());
+    }
+  }
+  to_remove
+}
+
+pub async fn create_backup(pool: DatabasePool, directory: &str, keep_daily: u32, keep_weekly: u32) -> Result<BackupReport, anyhow::Error> {
+  let directory = Path::new(directory);
+  fs::create_dir_all(directory).await.context("Error creating backup directory")?;
+
+  let path = directory.join(Local::now().naive_local().format(BACKUP_FILE_FORMAT).to_string());
+  let partial_path = path.with_extension(PARTIAL_BACKUP_EXTENSION);
+  let _ = fs::remove_file(&partial_path).await;
+  if let Err(err) = pool.backup_to(&partial_path).await {
+    let _ = fs::remove_file(&partial_path).await;
+    return Err(err);
+  }
+  fs::rename(&partial_path, &path).await.context("Error moving backup into place")?;
+
+  let backups = list_backups(directory).await?;
+  let mut removed_backups = Vec::new();
+  for backup_path in backups_to_remove(&backups, keep_daily, keep_weekly) {
+    fs::remove_file(&backup_path).await.context("Error removing old backup")?;
+    removed_backups.push(backup_path.to_string_lossy().to_string());
+  }
+
+  Ok(BackupReport {
+    path: path.to_string_lossy().to_string(),
+    removed_backups
+  })
+}
+
+async fn backup_is_due(directory: &str, interval_hours: u32) -> Result<bool, anyhow::Error> {
+  let backups = match fs::metadata(directory).await {
+    Ok(_) => list_backups(Path::new(directory)).await?,
+    Err(_) => Vec::new()
+  };
+  Ok(match backups.first() {
+    Some((created_at, _)) => Local::now().naive_local() - *created_at >= chrono::Duration::hours(interval_hours as i64),
+    None => true
+  })
+}
+
+async fn run_scheduled_backup(pool: DatabasePool) -> Result<Option<BackupReport>, anyhow::Error> {
+  // Backups are off until the vault has been unlocked once and its settings row exists
+  let settings = match VaultSettings::load_if_created(&pool).await? {
+    Some(settings) => settings,
+    None => return Ok(None)
+  };
+  if let (Some(directory), Some(interval_hours)) = (settings.backup_directory, settings.backup_interval_hours) {
+    if interval_hours > 0 && backup_is_due(&directory, interval_hours).await? {
+      let report = create_backup(pool, &directory, settings.backup_keep_daily, settings.backup_keep_weekly).await?;
+      return Ok(Some(report));
+    }
+  }
+  Ok(None)
+}
+
+// Runs for the lifetime of the app, backing up whichever vault is open once its configured interval has elapsed
+pub async fn run_scheduled_backups<F>(cipherpad: Arc<Mutex<Cipherpad>>, on_backup: F)
+where
+  F: Fn(Result<BackupReport, anyhow::Error>) + Send + 'static
+{
+  loop {
+    tokio::time::sleep(SCHEDULE_CHECK_INTERVAL).await;
+    let pool = cipherpad.lock().await.pool.clone();
+    if let Some(pool) = pool {
+      match run_scheduled_backup(pool).await {
+        Ok(Some(report)) => on_backup(Ok(report)),
+        Ok(None) => {},
+        Err(err) => on_backup(Err(err))
+      }
+    }
+  }
+}
\ No newline at end of file
@@ -1,11 +1,18 @@
+use std::sync::{Arc, Mutex};
+
 use anyhow::{bail, Result};
 use argon2::Argon2;
-use ring::{rand::{SecureRandom, SystemRandom}, aead::{UnboundKey, AES_256_GCM, Nonce, Aad, LessSafeKey}, hkdf};
+use ring::{rand::{SecureRandom, SystemRandom}, aead::{UnboundKey, AES_256_GCM, Nonce, Aad, LessSafeKey}, hkdf, hmac};
 
 pub const SALT_SIZE: usize = 16;
 pub const INFO_SIZE: usize = 16;
 pub const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const COMPRESSED_MARKER: [u8; 2] = [0xFF, b'Z']; // Prefixes compressed text, 0xFF never appears in UTF-8 text
+pub const COMPRESSION_LEVEL: i32 = 3;
+const CONTENT_HASH_INFO: &[u8] = b"cipherpad content hash";
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + INFO_SIZE + TAG_SIZE; // Bytes added to data by encrypt
 
 pub fn derive_key(password: &[u8], salt: &[u8], key: &mut [u8]) -> Result<()>{
   match Argon2::default().hash_password_into(password, salt, key) {
@@ -107,6 +114,18 @@ pub fn encrypt(data: &[u8], master_key: &[u8]) -> Result<Vec<u8>> {
   }
 }
 
+// Compresses UTF-8 text with zstd before encrypting it when that makes it smaller, decrypt_compressed decompresses it again
+pub fn encrypt_compressed(data: &[u8], master_key: &[u8]) -> Result<Vec<u8>> {
+  let compressed_data = zstd::bulk::compress(data, COMPRESSION_LEVEL)?;
+  if compressed_data.len() + COMPRESSED_MARKER.len() >= data.len() {
+    return encrypt(data, master_key);
+  }
+  let mut marked_data = Vec::with_capacity(compressed_data.len() + COMPRESSED_MARKER.len());
+  marked_data.extend(COMPRESSED_MARKER);
+  marked_data.extend(compressed_data);
+  encrypt(&marked_data, master_key)
+}
+
 pub fn decrypt(data: &[u8], master_key: &[u8]) -> Result<Vec<u8>> {
   let (nonce, info, encrypted_data) = split_nonce_info_and_encrypted_data(data)?;
 
@@ -119,6 +138,15 @@ pub fn decrypt(data: &[u8], master_key: &[u8]) -> Result<Vec<u8>> {
   }
 }
 
+// Only for data written by encrypt_compressed or as plain UTF-8 text, where the marker cannot occur by chance
+pub fn decrypt_compressed(data: &[u8], master_key: &[u8]) -> Result<Vec<u8>> {
+  let decrypted_data = decrypt(data, master_key)?;
+  if decrypted_data.starts_with(&COMPRESSED_MARKER) {
+    return Ok(zstd::stream::decode_all(&decrypted_data[COMPRESSED_MARKER.len()..])?);
+  }
+  Ok(decrypted_data)
+}
+
 pub fn decrypt_as_string(data: &[u8], master_key: &[u8]) -> Result<String> {
   match decrypt(&data, &master_key) {
     Ok(decrypted_data) => {
@@ -129,4 +157,153 @@ pub fn decrypt_as_string(data: &[u8], master_key: &[u8]) -> Result<String> {
     },
     Err(err) => Err(err)
   }
-}
\ No newline at end of file
+}
+
+fn derive_aead_key(master_key: &[u8], info: &[u8]) -> Result<LessSafeKey> {
+  let mut key = [0u8; KEY_SIZE];
+  if hkdf_derive_key(master_key, info, &mut key).is_err() { bail!("Derive failed") };
+  match UnboundKey::new(&AES_256_GCM, &key) {
+    Ok(unbound_key) => Ok(LessSafeKey::new(unbound_key)),
+    Err(err) => bail!("Error creating key: {}", err)
+  }
+}
+
+// Encrypts the chunks of a blob under a single derived key, producing the same layout as encrypt
+pub struct ChunkSealer {
+  key: LessSafeKey,
+  info: [u8; INFO_SIZE],
+  rng: SystemRandom
+}
+
+impl ChunkSealer {
+  pub fn new(master_key: &[u8]) -> Result<Self> {
+    let rng = SystemRandom::new();
+    let mut info = [0u8; INFO_SIZE];
+    if rng.fill(&mut info).is_err() { bail!("Error generating info") };
+    let key = derive_aead_key(master_key, &info)?;
+    Ok(Self {
+      key,
+      info,
+      rng
+    })
+  }
+
+  pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    if self.rng.fill(&mut nonce).is_err() { bail!("Error generating nonce") };
+    let mut merged_data = Vec::with_capacity(data.len() + ENCRYPTION_OVERHEAD);
+    merged_data.extend(nonce);
+    merged_data.extend(self.info);
+    merged_data.extend(data);
+    let nonce = Nonce::assume_unique_for_key(nonce);
+    match self.key.seal_in_place_separate_tag(nonce, Aad::empty(), &mut merged_data[NONCE_SIZE + INFO_SIZE..]) {
+      Ok(tag) => {
+        merged_data.extend(tag.as_ref());
+        Ok(merged_data)
+      },
+      Err(err) => bail!("Encryption failed: {}", err)
+    }
+  }
+}
+
+// Decrypts chunks from either encrypt or ChunkSealer, reusing the derived key while the info stays the same
+pub struct ChunkOpener {
+  master_key: Vec<u8>,
+  last_key: Mutex<Option<([u8; INFO_SIZE], Arc<LessSafeKey>)>>
+}
+
+impl ChunkOpener {
+  pub fn new(master_key: &[u8]) -> Self {
+    Self {
+      master_key: master_key.to_vec(),
+      last_key: Mutex::new(None)
+    }
+  }
+
+  fn key_for_info(&self, info: [u8; INFO_SIZE]) -> Result<Arc<LessSafeKey>> {
+    let mut last_key = match self.last_key.lock() {
+      Ok(last_key) => last_key,
+      Err(_) => bail!("Key cache was poisoned")
+    };
+    if let Some((last_info, key)) = last_key.as_ref() {
+      if *last_info == info {
+        return Ok(key.clone());
+      }
+    }
+    let key = Arc::new(derive_aead_key(&self.master_key, &info)?);
+    *last_key = Some((info, key.clone()));
+    Ok(key)
+  }
+
+  pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < ENCRYPTION_OVERHEAD {
+      bail!("Invalid info or nonce lengths")
+    }
+    let info: [u8; INFO_SIZE] = data[NONCE_SIZE..NONCE_SIZE + INFO_SIZE].try_into()?;
+    let key = self.key_for_info(info)?;
+    let nonce = match Nonce::try_assume_unique_for_key(&data[..NONCE_SIZE]) {
+      Ok(nonce) => nonce,
+      Err(err) => bail!("Decryption failed: {}", err)
+    };
+    let mut in_out = data[NONCE_SIZE + INFO_SIZE..].to_vec();
+    match key.open_in_place(nonce, Aad::empty(), &mut in_out) {
+      Ok(decrypted_data) => {
+        let decrypted_len = decrypted_data.len();
+        in_out.truncate(decrypted_len);
+        Ok(in_out)
+      },
+      Err(err) => bail!("Decryption failed: {}", err)
+    }
+  }
+}
+
+// Keyed hash of plaintext under a vault subkey, so identical content can be matched without revealing its hash
+pub struct ContentHasher {
+  context: hmac::Context
+}
+
+impl ContentHasher {
+  pub fn new(master_key: &[u8]) -> Result<Self> {
+    let mut key = [0u8; KEY_SIZE];
+    if hkdf_derive_key(master_key, CONTENT_HASH_INFO, &mut key).is_err() { bail!("Derive failed") };
+    Ok(Self {
+      context: hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, &key))
+    })
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    self.context.update(data);
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.context.sign().as_ref().to_vec()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MASTER_KEY: [u8; 32] = [7; 32];
+
+  #[test]
+  fn compressed_text_round_trips() {
+    let text = "cipherpad ".repeat(200);
+    let encrypted = encrypt_compressed(text.as_bytes(), &MASTER_KEY).unwrap();
+    assert!(encrypted.len() < text.len());
+    assert_eq!(decrypt_compressed(&encrypted, &MASTER_KEY).unwrap(), text.as_bytes());
+  }
+
+  #[test]
+  fn uncompressed_text_round_trips_through_decrypt_compressed() {
+    let encrypted = encrypt(b"short", &MASTER_KEY).unwrap();
+    assert_eq!(decrypt_compressed(&encrypted, &MASTER_KEY).unwrap(), b"short");
+  }
+
+  #[test]
+  fn decrypt_keeps_binary_that_starts_with_the_marker() {
+    let data = [&COMPRESSED_MARKER[..], b"not zstd"].concat();
+    let encrypted = encrypt(&data, &MASTER_KEY).unwrap();
+    assert_eq!(decrypt(&encrypted, &MASTER_KEY).unwrap(), data);
+  }
+}
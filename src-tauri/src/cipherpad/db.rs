@@ -1,19 +1,27 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc, time::Duration};
 
 use anyhow::{Context, bail};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{ToSql, params_from_iter, types::{Value, FromSql, FromSqlError}, blob::Blob};
+use rusqlite::{backup::Backup, Connection, ToSql, Transaction, params_from_iter, types::{Value, FromSql, FromSqlError}, blob::Blob};
+
+#[derive(Clone)]
 pub struct DatabasePool {
   pool: Arc<Pool<SqliteConnectionManager>>
 }
 
+const BACKUP_PAGES_PER_STEP: i32 = 256;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10); // Lets other connections write between backup steps
+
 type SqlParam = Box<dyn ToSql + Sync + Send>;
 type SqlParams = Vec<SqlParam>;
 
 impl DatabasePool {
   pub fn new(db_path: &str) -> Result<Self, anyhow::Error> {
-    let manager = SqliteConnectionManager::file(db_path);
+    // secure_delete zeroes freed pages inside the database file. The rollback journal is still unlinked without
+    // being overwritten, so old pages it held may linger in free disk space until the filesystem reuses it.
+    let manager = SqliteConnectionManager::file(db_path)
+      .with_init(|conn| conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA secure_delete = ON;"));
     let pool = Pool::new(manager)?;
     Ok(Self { pool: Arc::new(pool) })
   }
@@ -60,6 +68,16 @@ impl DatabasePool {
     }
   }
 
+  pub async fn add_column_if_not_exists(&self, table: &str, column: &str, definition: &str) -> Result<(), anyhow::Error> {
+    let columns = self.select_query(&format!("SELECT name FROM pragma_table_info('{}')", table), vec![], 1).await?;
+    let column_exists = columns.iter()
+      .any(|row| matches!(row.get(0), Some(Value::Text(name)) if name == column));
+    if !column_exists {
+      self.execute_query(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition), vec![]).await?;
+    }
+    Ok(())
+  }
+
   pub async fn begin_transaction(&self) -> Result<(), anyhow::Error> {
     self.execute_query("BEGIN TRANSACTION;", SqlParamsBuilder::new().build()).await?;
     Ok(())
@@ -70,6 +88,40 @@ impl DatabasePool {
     Ok(())
   }
 
+  pub async fn transaction<F, R>(&self, func: F) -> Result<R, anyhow::Error>
+  where
+    F: FnOnce(&Transaction) -> Result<R, anyhow::Error> + Send + 'static,
+    R: Send + 'static
+  {
+    let pool = self.pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+      let mut conn = pool.get().context("Error getting DB connection")?;
+      let transaction = conn.transaction().context("Error beginning transaction")?;
+      let result = func(&transaction)?;
+      transaction.commit().context("Error committing transaction")?;
+      Ok(result)
+    }).await?
+  }
+
+  pub async fn vacuum(&self) -> Result<(), anyhow::Error> {
+    self.execute_query("VACUUM;", vec![]).await?;
+    Ok(())
+  }
+
+  // Uses the SQLite online backup API, so the copy is consistent even while other connections write to the vault
+  pub async fn backup_to(&self, path: &Path) -> Result<(), anyhow::Error> {
+    let pool = self.pool.clone();
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+      let conn = pool.get().context("Error getting DB connection")?;
+      let mut backup_conn = Connection::open(&path).context("Error opening backup file")?;
+      let backup = Backup::new(&conn, &mut backup_conn).context("Error starting backup")?;
+      backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, None).context("Error running backup")?;
+      Ok(())
+    }).await?
+  }
 
   pub async fn open_blob<F, R>(&self, row_id: i64, column: &str, table: &str, read_only: bool, func: F) -> Result<R, anyhow::Error> 
   where
@@ -0,0 +1,259 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::bail;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::fs;
+use uuid::Uuid;
+
+use super::{DatabasePool, EncryptedPad, Job, PadMap, utils::sanitize_file_name};
+
+const TEXT_EXTENSIONS: [&str; 2] = ["md", "txt"];
+const TEXT_EXTENSION: &str = "md"; // Added to text pads whose name has no text extension
+
+#[derive(Serialize)]
+pub struct ExportFailure {
+  id: Uuid,
+  name: String,
+  error: String
+}
+
+#[derive(Serialize)]
+pub struct ExportReport {
+  #[serde(rename = "exportedPads")]
+  exported_pads: usize,
+  failures: Vec<ExportFailure>
+}
+
+struct ExportMetadata {
+  pad_type: String,
+  name: String,
+  file_name: Option<String>
+}
+
+impl ExportMetadata {
+  fn parse(encrypted_pad: &EncryptedPad) -> Result<Self, anyhow::Error> {
+    let metadata: Value = serde_json::from_str(&encrypted_pad.metadata)?;
+    let field = |key: &str| metadata.get(key).and_then(Value::as_str).map(|value| value.to_string());
+    Ok(Self {
+      pad_type: field("type").unwrap_or_default(),
+      name: field("name").unwrap_or_default(),
+      file_name: field("fileName")
+    })
+  }
+}
+
+// Pads whose metadata cannot be read are named after their id
+fn export_name(encrypted_pad: &EncryptedPad, metadata: &Result<ExportMetadata, anyhow::Error>) -> String {
+  match metadata {
+    Ok(metadata) => metadata.name.clone(),
+    Err(_) => encrypted_pad.id.to_string()
+  }
+}
+
+// Picks a name that is not already taken in the directory, case insensitively since not every filesystem is case sensitive
+fn unique_path(directory: &Path, file_name: &str, used_names: &mut HashMap<PathBuf, HashSet<String>>) -> PathBuf {
+  let used_names = used_names.entry(directory.to_path_buf()).or_default();
+  let file_path = Path::new(file_name);
+  let stem = file_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+  let extension = file_path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+
+  let mut candidate = file_name.to_string();
+  let mut counter = 2;
+  while used_names.contains(&candidate.to_lowercase()) || directory.join(&candidate).exists() {
+    candidate = format!("{} ({}){}", stem, counter, extension);
+    counter += 1;
+  }
+  used_names.insert(candidate.to_lowercase());
+  directory.join(candidate)
+}
+
+fn text_file_name(name: &str) -> String {
+  let name = sanitize_file_name(name);
+  let has_text_extension = Path::new(&name).extension()
+    .map(|extension| TEXT_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+    .unwrap_or(false);
+  if has_text_extension {
+    name
+  } else {
+    format!("{}.{}", name, TEXT_EXTENSION)
+  }
+}
+
+struct PadExporter<'a> {
+  pool: &'a DatabasePool,
+  master_key: &'a [u8],
+  job: Arc<Job>,
+  used_names: HashMap<PathBuf, HashSet<String>>
+}
+
+async fn export_pad(exporter: &mut PadExporter<'_>, encrypted_pad: &EncryptedPad, metadata: &ExportMetadata, directory: &Path, has_children: bool) -> Result<bool, anyhow::Error> {
+  let PadExporter { pool, master_key, job, used_names } = exporter;
+  match metadata.pad_type.as_str() {
+    "text" => {
+      let pad_data: Value = serde_json::from_str(&encrypted_pad.clone().decrypt_pad_data(master_key, pool).await?)?;
+      let text = pad_data.get("text").and_then(Value::as_str).unwrap_or_default();
+      // Pads that only group other pads, such as imported folders, are exported as just the folder
+      if has_children && text.is_empty() {
+        return Ok(false);
+      }
+      let file_path = unique_path(directory, &text_file_name(&metadata.name), used_names);
+      fs::write(&file_path, text).await?;
+      Ok(true)
+    },
+    "blob" => {
+      let file_name = sanitize_file_name(metadata.file_name.as_deref().unwrap_or(&metadata.name));
+      let file_path = unique_path(directory, &file_name, used_names);
+      job.set_current_item(file_path.display().to_string());
+      let file_path = match file_path.to_str() {
+        Some(file_path) => file_path.to_string(),
+        None => bail!("Path is not valid UTF-8")
+      };
+      encrypted_pad.clone().decrypt_pad_to_file(pool, master_key, &file_path, job.clone()).await?;
+      Ok(true)
+    },
+    pad_type => bail!("Unknown pad type {}", pad_type)
+  }
+}
+
+// Pads with children become folders named after the pad, with the pad's own content exported inside
+pub async fn export_subtree(pool: &DatabasePool, master_key: &[u8], pad_map: PadMap, id: Uuid, directory: &str, job: Arc<Job>) -> Result<ExportReport, anyhow::Error> {
+  let directory = PathBuf::from(directory);
+  if !fs::metadata(&directory).await?.is_dir() {
+    bail!("{} is not a directory", directory.display())
+  }
+  let root = match pad_map.pads.get(&id) {
+    Some(root) => root.clone(),
+    None => bail!("No pad with that id")
+  };
+
+  // Metadata is only parsed for pads in the exported subtree, as they are reached
+  let mut children: HashMap<Uuid, Vec<EncryptedPad>> = HashMap::new();
+  for encrypted_pad in pad_map.pads.into_values() {
+    if let Some(parent_id) = encrypted_pad.parent_id {
+      children.entry(parent_id).or_default().push(encrypted_pad);
+    }
+  }
+
+  let mut exporter = PadExporter {
+    pool,
+    master_key,
+    job: job.clone(),
+    used_names: HashMap::new()
+  };
+  let mut exported_pads = 0;
+  let mut failures = Vec::new();
+  let root_metadata = ExportMetadata::parse(&root);
+  let mut stack = vec![(root, root_metadata, directory)];
+  while let Some((encrypted_pad, metadata, directory)) = stack.pop() {
+    job.check_cancelled()?;
+    let name = export_name(&encrypted_pad, &metadata);
+    let metadata = match metadata {
+      Ok(metadata) => Some(metadata),
+      Err(err) => {
+        failures.push(ExportFailure {
+          id: encrypted_pad.id,
+          name: name.clone(),
+          error: format!("Unreadable metadata: {}", err)
+        });
+        None
+      }
+    };
+    let mut pad_children: Vec<(EncryptedPad, Result<ExportMetadata, anyhow::Error>)> = children.remove(&encrypted_pad.id)
+      .unwrap_or_default()
+      .into_iter()
+      .map(|child| {
+        let child_metadata = ExportMetadata::parse(&child);
+        (child, child_metadata)
+      })
+      .collect();
+    let has_children = !pad_children.is_empty();
+    let export_directory = if has_children {
+      let folder_path = unique_path(&directory, &sanitize_file_name(&name), &mut exporter.used_names);
+      if let Err(err) = fs::create_dir(&folder_path).await {
+        failures.push(ExportFailure {
+          id: encrypted_pad.id,
+          name,
+          error: err.to_string()
+        });
+        continue;
+      }
+      pad_children.sort_by_cached_key(|(child, child_metadata)| std::cmp::Reverse(export_name(child, child_metadata)));
+      for (child, child_metadata) in pad_children {
+        stack.push((child, child_metadata, folder_path.clone()));
+      }
+      folder_path
+    } else {
+      directory
+    };
+
+    let metadata = match metadata {
+      Some(metadata) => metadata,
+      None => continue
+    };
+    match export_pad(&mut exporter, &encrypted_pad, &metadata, &export_directory, has_children).await {
+      Ok(true) => exported_pads += 1,
+      Ok(false) => {},
+      Err(err) => {
+        job.check_cancelled()?;
+        failures.push(ExportFailure {
+          id: encrypted_pad.id,
+          name: metadata.name,
+          error: err.to_string()
+        });
+      }
+    }
+  }
+
+  Ok(ExportReport {
+    exported_pads,
+    failures
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Never created, so only used_names can clash
+  fn missing_directory() -> PathBuf {
+    std::env::temp_dir().join(Uuid::new_v4().to_string())
+  }
+
+  #[test]
+  fn clashing_names_are_numbered_before_the_extension() {
+    let directory = missing_directory();
+    let mut used_names = HashMap::new();
+    assert_eq!(unique_path(&directory, "notes.md", &mut used_names), directory.join("notes.md"));
+    assert_eq!(unique_path(&directory, "notes.md", &mut used_names), directory.join("notes (2).md"));
+    assert_eq!(unique_path(&directory, "notes.md", &mut used_names), directory.join("notes (3).md"));
+    assert_eq!(unique_path(&directory, "notes", &mut used_names), directory.join("notes"));
+    assert_eq!(unique_path(&directory, "notes", &mut used_names), directory.join("notes (2)"));
+  }
+
+  #[test]
+  fn names_clash_case_insensitively() {
+    let directory = missing_directory();
+    let mut used_names = HashMap::new();
+    unique_path(&directory, "Photo.JPG", &mut used_names);
+    assert_eq!(unique_path(&directory, "photo.jpg", &mut used_names), directory.join("photo (2).jpg"));
+  }
+
+  #[test]
+  fn names_only_clash_within_a_directory() {
+    let directory = missing_directory();
+    let mut used_names = HashMap::new();
+    unique_path(&directory, "notes.md", &mut used_names);
+    assert_eq!(unique_path(&directory.join("child"), "notes.md", &mut used_names), directory.join("child").join("notes.md"));
+  }
+
+  #[test]
+  fn existing_files_are_not_overwritten() {
+    let directory = missing_directory();
+    std::fs::create_dir(&directory).unwrap();
+    std::fs::write(directory.join("notes.md"), b"").unwrap();
+    let file_path = unique_path(&directory, "notes.md", &mut HashMap::new());
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(file_path, directory.join("notes (2).md"));
+  }
+}
\ No newline at end of file
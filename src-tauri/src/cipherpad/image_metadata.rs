@@ -0,0 +1,185 @@
+use anyhow::bail;
+use img_parts::{jpeg::{markers, Jpeg}, png::Png, webp::{WebP, CHUNK_XMP}, Bytes, ImageEXIF};
+
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PNG_TEXT_CHUNKS: [([u8; 4], &str); 4] = [
+  (*b"tEXt", "Text"),
+  (*b"zTXt", "Compressed text"),
+  (*b"iTXt", "International text"),
+  (*b"tIME", "Modification time")
+];
+
+pub fn can_strip_metadata(media_type: &str) -> bool {
+  matches!(media_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+fn push_field(stripped_fields: &mut Vec<String>, field: String) {
+  if !stripped_fields.contains(&field) {
+    stripped_fields.push(field);
+  }
+}
+
+// Returns an EXIF block holding only the orientation, which phone photos rely on to display upright
+fn orientation_exif(exif: &exif::Exif) -> Option<Bytes> {
+  let orientation = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+  let mut writer = exif::experimental::Writer::new();
+  writer.push_field(orientation);
+  let mut orientation_exif = std::io::Cursor::new(Vec::new());
+  writer.write(&mut orientation_exif, exif.little_endian()).ok()?;
+  Some(Bytes::from(orientation_exif.into_inner()))
+}
+
+fn strip_exif(stripped_fields: &mut Vec<String>, exif: Bytes) -> Option<Bytes> {
+  match exif::Reader::new().read_raw(exif.to_vec()) {
+    Ok(exif) => {
+      for field in exif.fields() {
+        let tag = field.tag.to_string();
+        if field.tag != exif::Tag::Orientation && !tag.ends_with("IFDPointer") {
+          push_field(stripped_fields, tag);
+        }
+      }
+      orientation_exif(&exif)
+    },
+    Err(_) => {
+      push_field(stripped_fields, "EXIF".to_string());
+      None
+    }
+  }
+}
+
+// Rewrites the image without EXIF, XMP, IPTC and text metadata and returns the names of the removed fields.
+// Colour profiles and the EXIF orientation are kept because they change how the image looks.
+pub fn strip_metadata(data: Vec<u8>, media_type: &str) -> Result<(Vec<u8>, Vec<String>), anyhow::Error> {
+  let data = Bytes::from(data);
+  let mut stripped_fields = Vec::new();
+  let stripped_data = match media_type {
+    "image/jpeg" => {
+      let mut jpeg = Jpeg::from_bytes(data)?;
+      let kept_exif = jpeg.exif().and_then(|exif| strip_exif(&mut stripped_fields, exif));
+      for segment in jpeg.segments() {
+        match segment.marker() {
+          markers::APP1 if segment.contents().starts_with(XMP_PREFIX) => push_field(&mut stripped_fields, "XMP".to_string()),
+          markers::APP13 => push_field(&mut stripped_fields, "IPTC".to_string()),
+          markers::COM => push_field(&mut stripped_fields, "Comment".to_string()),
+          _ => {}
+        }
+      }
+      jpeg.segments_mut().retain(|segment| !matches!(segment.marker(), markers::APP1 | markers::APP13 | markers::COM));
+      // set_exif inserts the segment after the first three, which every decodable JPEG has
+      if jpeg.segments().len() >= 3 {
+        jpeg.set_exif(kept_exif);
+      }
+      jpeg.encoder().bytes()
+    },
+    "image/png" => {
+      let mut png = Png::from_bytes(data)?;
+      let kept_exif = png.exif().and_then(|exif| strip_exif(&mut stripped_fields, exif));
+      png.set_exif(kept_exif);
+      for (kind, name) in PNG_TEXT_CHUNKS {
+        if png.chunk_by_type(kind).is_some() {
+          push_field(&mut stripped_fields, name.to_string());
+          png.remove_chunks_by_type(kind);
+        }
+      }
+      png.encoder().bytes()
+    },
+    "image/webp" => {
+      let mut webp = WebP::from_bytes(data)?;
+      let kept_exif = webp.exif().and_then(|exif| strip_exif(&mut stripped_fields, exif));
+      if webp.has_chunk(CHUNK_XMP) {
+        push_field(&mut stripped_fields, "XMP".to_string());
+        webp.remove_chunks_by_id(CHUNK_XMP);
+      }
+      // Also updates the VP8X flags for the removed chunks
+      webp.set_exif(kept_exif);
+      webp.encoder().bytes()
+    },
+    _ => bail!("Removing metadata from {} is not supported", media_type)
+  };
+  Ok((stripped_data.to_vec(), stripped_fields))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use image::{DynamicImage, ImageOutputFormat};
+  use img_parts::{jpeg::JpegSegment, png::PngChunk};
+
+  use super::*;
+
+  fn encode_image(format: ImageOutputFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    DynamicImage::new_rgb8(4, 4).write_to(&mut Cursor::new(&mut data), format).unwrap();
+    data
+  }
+
+  fn ascii_field(tag: exif::Tag, value: &str) -> exif::Field {
+    exif::Field { tag, ifd_num: exif::In::PRIMARY, value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]) }
+  }
+
+  // Camera make and model plus an upside down orientation
+  fn camera_exif() -> Bytes {
+    let make = ascii_field(exif::Tag::Make, "Camera maker");
+    let model = ascii_field(exif::Tag::Model, "Camera model");
+    let orientation = exif::Field { tag: exif::Tag::Orientation, ifd_num: exif::In::PRIMARY, value: exif::Value::Short(vec![3]) };
+    let mut writer = exif::experimental::Writer::new();
+    writer.push_field(&make);
+    writer.push_field(&model);
+    writer.push_field(&orientation);
+    let mut exif = Cursor::new(Vec::new());
+    writer.write(&mut exif, false).unwrap();
+    Bytes::from(exif.into_inner())
+  }
+
+  fn exif_tags(exif: Option<Bytes>) -> Vec<exif::Tag> {
+    let exif = exif::Reader::new().read_raw(exif.unwrap().to_vec()).unwrap();
+    exif.fields().map(|field| field.tag).filter(|tag| !tag.to_string().ends_with("IFDPointer")).collect()
+  }
+
+  #[test]
+  fn jpeg_keeps_only_orientation() {
+    let mut jpeg = Jpeg::from_bytes(Bytes::from(encode_image(ImageOutputFormat::Jpeg(90)))).unwrap();
+    jpeg.set_exif(Some(camera_exif()));
+    jpeg.segments_mut().insert(3, JpegSegment::new_with_contents(markers::COM, Bytes::from_static(b"Taken on holiday")));
+    let mut xmp = XMP_PREFIX.to_vec();
+    xmp.extend_from_slice(b"<x:xmpmeta/>");
+    jpeg.segments_mut().insert(3, JpegSegment::new_with_contents(markers::APP1, Bytes::from(xmp)));
+
+    let (data, stripped_fields) = strip_metadata(jpeg.encoder().bytes().to_vec(), "image/jpeg").unwrap();
+    assert_eq!(stripped_fields, ["Make", "Model", "XMP", "Comment"]);
+    let jpeg = Jpeg::from_bytes(Bytes::from(data.clone())).unwrap();
+    assert_eq!(exif_tags(jpeg.exif()), [exif::Tag::Orientation]);
+    assert!(!jpeg.segments().iter().any(|segment| segment.marker() == markers::COM));
+    image::load_from_memory(&data).unwrap();
+  }
+
+  #[test]
+  fn png_text_chunks_are_removed() {
+    let mut png = Png::from_bytes(Bytes::from(encode_image(ImageOutputFormat::Png))).unwrap();
+    png.set_exif(Some(camera_exif()));
+    let end = png.chunks().len() - 1;
+    png.chunks_mut().insert(end, PngChunk::new(*b"tEXt", Bytes::from_static(b"Author\0Someone")));
+
+    let (data, stripped_fields) = strip_metadata(png.encoder().bytes().to_vec(), "image/png").unwrap();
+    assert_eq!(stripped_fields, ["Make", "Model", "Text"]);
+    let png = Png::from_bytes(Bytes::from(data.clone())).unwrap();
+    assert_eq!(exif_tags(png.exif()), [exif::Tag::Orientation]);
+    assert!(png.chunk_by_type(*b"tEXt").is_none());
+    image::load_from_memory(&data).unwrap();
+  }
+
+  #[test]
+  fn image_without_metadata_is_unchanged() {
+    let data = encode_image(ImageOutputFormat::Png);
+    let (stripped_data, stripped_fields) = strip_metadata(data.clone(), "image/png").unwrap();
+    assert!(stripped_fields.is_empty());
+    assert_eq!(stripped_data, data);
+  }
+
+  #[test]
+  fn unsupported_media_type() {
+    assert!(!can_strip_metadata("image/gif"));
+    assert!(strip_metadata(encode_image(ImageOutputFormat::Png), "image/gif").is_err());
+  }
+}
\ No newline at end of file
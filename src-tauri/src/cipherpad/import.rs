@@ -0,0 +1,267 @@
+use std::{fmt::Display, path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::bail;
+use rusqlite::params;
+use serde::Serialize;
+use serde_json::json;
+use tokio::fs;
+use uuid::Uuid;
+
+use super::{
+  BlobPadMetadata, DatabasePool, Job, VaultSettings, WrittenBlob, BlobOptions,
+  crypto, thumbnail, encrypt_to_temp_file, read_file_for_import, store_blob_content,
+  jobs::JobReader,
+  utils::{chunk_size_for_file, is_compressed_media_type, media_kind, millis_since_epoch, now_millis, MediaKind}
+};
+
+#[derive(Serialize)]
+pub struct ImportFailure {
+  path: String,
+  error: String
+}
+
+impl ImportFailure {
+  fn new<E: Display>(path: &Path, error: E) -> Self {
+    Self {
+      path: path.display().to_string(),
+      error: error.to_string()
+    }
+  }
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+  #[serde(rename = "rootId")]
+  root_id: Uuid,
+  #[serde(rename = "importedFolders")]
+  imported_folders: usize,
+  #[serde(rename = "importedFiles")]
+  imported_files: usize,
+  failures: Vec<ImportFailure>
+}
+
+struct ImportEntry {
+  id: Uuid,
+  parent_id: Option<Uuid>,
+  path: PathBuf,
+  name: String,
+  created_at: u64,
+  modified_at: u64,
+  is_dir: bool
+}
+
+struct ImportedFolder {
+  id: Uuid,
+  parent_id: Option<Uuid>,
+  encrypted_metadata: Vec<u8>,
+  encrypted_data: Vec<u8>
+}
+
+struct ImportedFile {
+  id: Uuid,
+  parent_id: Option<Uuid>,
+  encrypted_metadata: Vec<u8>,
+  encrypted_thumbnail: Option<Vec<u8>>,
+  written_blob: WrittenBlob,
+  temp_path: PathBuf
+}
+
+impl ImportEntry {
+  fn new(path: &Path, parent_id: Option<Uuid>, metadata: &std::fs::Metadata) -> Self {
+    let modified_at = metadata.modified().ok().and_then(millis_since_epoch).unwrap_or_else(now_millis);
+    Self {
+      id: Uuid::new_v4(),
+      parent_id,
+      path: path.to_path_buf(),
+      name: path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string()),
+      created_at: metadata.created().ok().and_then(millis_since_epoch).unwrap_or(modified_at),
+      modified_at,
+      is_dir: metadata.is_dir()
+    }
+  }
+}
+
+// Walks the directory breadth first, so every folder comes before its contents
+fn walk_directory(root: &Path, parent_id: Option<Uuid>) -> Result<(Vec<ImportEntry>, Vec<ImportFailure>), anyhow::Error> {
+  let root_metadata = std::fs::metadata(root)?;
+  if !root_metadata.is_dir() {
+    bail!("{} is not a directory", root.display())
+  }
+  let mut entries = vec![ImportEntry::new(root, parent_id, &root_metadata)];
+  let mut failures = Vec::new();
+
+  let mut index = 0;
+  while index < entries.len() {
+    if entries[index].is_dir {
+      let (directory, directory_id) = (entries[index].path.clone(), entries[index].id);
+      let children = std::fs::read_dir(&directory)
+        .and_then(|read_dir| read_dir.map(|entry| entry.map(|entry| entry.path())).collect::<std::io::Result<Vec<PathBuf>>>());
+      match children {
+        Ok(mut children) => {
+          children.sort();
+          for child in children {
+            // Symbolic links are not followed so a link back up the tree cannot loop forever
+            match std::fs::symlink_metadata(&child) {
+              Ok(metadata) if metadata.is_dir() || metadata.is_file() => {
+                entries.push(ImportEntry::new(&child, Some(directory_id), &metadata));
+              },
+              Ok(_) => failures.push(ImportFailure::new(&child, "Only regular files and folders can be imported")),
+              Err(err) => failures.push(ImportFailure::new(&child, err))
+            }
+          }
+        },
+        Err(err) => failures.push(ImportFailure::new(&directory, err))
+      }
+    }
+    index += 1;
+  }
+  Ok((entries, failures))
+}
+
+fn import_folder(entry: &ImportEntry, master_key: &[u8]) -> Result<ImportedFolder, anyhow::Error> {
+  let metadata = json!({
+    "type": "text",
+    "name": entry.name,
+    "createdAt": entry.created_at,
+    "lastModifiedAt": entry.modified_at
+  });
+  let data = json!({
+    "text": "",
+    "revisionHistory": []
+  });
+  Ok(ImportedFolder {
+    id: entry.id,
+    parent_id: entry.parent_id,
+    encrypted_metadata: crypto::encrypt(metadata.to_string().as_bytes(), master_key)?,
+    encrypted_data: crypto::encrypt_compressed(data.to_string().as_bytes(), master_key)?
+  })
+}
+
+async fn import_file(entry: &ImportEntry, master_key: &[u8], strip_metadata: bool, job: Arc<Job>) -> Result<ImportedFile, anyhow::Error> {
+  let file_path = match entry.path.to_str() {
+    Some(file_path) => file_path.to_string(),
+    None => bail!("Path is not valid UTF-8")
+  };
+  let mut blob_pad_metadata: BlobPadMetadata = serde_json::from_value(json!({
+    "type": "blob",
+    "name": entry.name,
+    "fileName": entry.name,
+    "createdAt": entry.created_at,
+    "lastModifiedAt": entry.modified_at
+  }))?;
+  let (file_reader, total_bytes, _) = read_file_for_import(&file_path, &mut blob_pad_metadata, strip_metadata).await?;
+  let media_type = blob_pad_metadata.media_type.clone().unwrap_or_default();
+
+  let options = BlobOptions {
+    chunk_size: chunk_size_for_file(total_bytes),
+    compress: !is_compressed_media_type(&media_type)
+  };
+  job.set_current_item(file_path.clone());
+  let reader_job = job.clone();
+  let (temp_path, written_blob, _) = encrypt_to_temp_file(master_key, options, job, move |pad_writer| {
+    let mut file_reader = JobReader::new(file_reader, reader_job, total_bytes);
+    std::io::copy(&mut file_reader, pad_writer)?;
+    Ok(())
+  }).await?;
+
+  blob_pad_metadata.record_written_blob(&written_blob);
+  let encrypted_metadata = match serde_json::to_string(&blob_pad_metadata)
+    .map_err(anyhow::Error::from)
+    .and_then(|blob_pad_metadata| crypto::encrypt(blob_pad_metadata.as_bytes(), master_key)) {
+    Ok(encrypted_metadata) => encrypted_metadata,
+    Err(err) => {
+      fs::remove_file(&temp_path).await?;
+      return Err(err);
+    }
+  };
+
+  // Same as a single import, images that fail to decode are imported without a thumbnail
+  let encrypted_thumbnail = match media_kind(&media_type) {
+    MediaKind::Image => match tokio::task::spawn_blocking(move || thumbnail::generate_thumbnail(&file_path)).await {
+      Ok(Ok(thumbnail)) => crypto::encrypt(&thumbnail, master_key).ok(),
+      _ => None
+    },
+    _ => None
+  };
+
+  Ok(ImportedFile {
+    id: entry.id,
+    parent_id: entry.parent_id,
+    encrypted_metadata,
+    encrypted_thumbnail,
+    written_blob,
+    temp_path
+  })
+}
+
+async fn import_entries(entries: &[ImportEntry], master_key: &[u8], strip_metadata: bool, job: &Arc<Job>, folders: &mut Vec<ImportedFolder>, files: &mut Vec<ImportedFile>, failures: &mut Vec<ImportFailure>) -> Result<(), anyhow::Error> {
+  for entry in entries {
+    job.check_cancelled()?;
+    if entry.is_dir {
+      folders.push(import_folder(entry, master_key)?);
+    } else {
+      match import_file(entry, master_key, strip_metadata, job.clone()).await {
+        Ok(file) => files.push(file),
+        Err(err) => {
+          // A cancelled job stops the whole import instead of being reported against one file
+          job.check_cancelled()?;
+          failures.push(ImportFailure::new(&entry.path, err));
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+// All pads are inserted in a single transaction so a cancelled or failed import leaves nothing behind
+async fn save_imported(pool: &DatabasePool, folders: Vec<ImportedFolder>, files: Vec<ImportedFile>, job: Arc<Job>) -> Result<(), anyhow::Error> {
+  pool.transaction(move |transaction| {
+    for folder in folders {
+      transaction.execute(
+        "INSERT INTO node (id, parent_id, pad_metadata, pad_data) VALUES (?1, ?2, ?3, ?4)",
+        params![folder.id, folder.parent_id, folder.encrypted_metadata, folder.encrypted_data]
+      )?;
+    }
+    for file in files {
+      store_blob_content(transaction, &file.written_blob, &file.temp_path, &job)?;
+      transaction.execute(
+        "INSERT INTO node (id, parent_id, pad_metadata, pad_data, content_hash, thumbnail) VALUES (?1, ?2, ?3, ZEROBLOB(0), ?4, ?5)",
+        params![file.id, file.parent_id, file.encrypted_metadata, file.written_blob.content_hash, file.encrypted_thumbnail]
+      )?;
+    }
+    Ok(())
+  }).await
+}
+
+pub async fn import_directory(pool: &DatabasePool, master_key: &[u8], directory: &str, parent_id: Option<Uuid>, strip_metadata: Option<bool>, job: Arc<Job>) -> Result<ImportReport, anyhow::Error> {
+  let strip_metadata = match strip_metadata {
+    Some(strip_metadata) => strip_metadata,
+    None => VaultSettings::load(pool).await?.strip_image_metadata
+  };
+  let root = PathBuf::from(directory);
+  let (entries, mut failures) = tokio::task::spawn_blocking(move || walk_directory(&root, parent_id)).await??;
+  let root_id = entries[0].id;
+
+  let mut folders = Vec::new();
+  let mut files = Vec::new();
+  let import_result = import_entries(&entries, master_key, strip_metadata, &job, &mut folders, &mut files, &mut failures).await;
+  let temp_paths: Vec<PathBuf> = files.iter().map(|file| file.temp_path.clone()).collect();
+  let (imported_folders, imported_files) = (folders.len(), files.len());
+  let save_result = match import_result {
+    Ok(_) => save_imported(pool, folders, files, job).await,
+    Err(err) => Err(err)
+  };
+  for temp_path in temp_paths {
+    fs::remove_file(&temp_path).await?;
+  }
+  save_result?;
+
+  Ok(ImportReport {
+    root_id,
+    imported_folders,
+    imported_files,
+    failures
+  })
+}
\ No newline at end of file
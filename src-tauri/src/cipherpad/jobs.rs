@@ -0,0 +1,104 @@
+use std::{io::{self, Read}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}};
+
+use anyhow::bail;
+use serde::Serialize;
+use uuid::Uuid;
+
+const PROGRESS_STEPS: u64 = 100; // Progress is reported at most this many times over a job
+
+#[derive(Clone, Serialize)]
+pub struct JobProgress {
+  pub id: Uuid,
+  #[serde(rename = "bytesDone")]
+  pub bytes_done: u64,
+  #[serde(rename = "totalBytes")]
+  pub total_bytes: u64,
+  #[serde(rename = "currentItem", skip_serializing_if = "Option::is_none")]
+  pub current_item: Option<String>
+}
+
+pub struct Job {
+  pub id: Uuid,
+  cancelled: AtomicBool,
+  last_reported: AtomicU64,
+  current_item: Mutex<Option<String>>,
+  on_progress: Box<dyn Fn(JobProgress) + Send + Sync>
+}
+
+impl Job {
+  pub fn new<F>(id: Uuid, on_progress: F) -> Self
+  where
+    F: Fn(JobProgress) + Send + Sync + 'static
+  {
+    Self {
+      id,
+      cancelled: AtomicBool::new(false),
+      last_reported: AtomicU64::new(0),
+      current_item: Mutex::new(None),
+      on_progress: Box::new(on_progress)
+    }
+  }
+
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn check_cancelled(&self) -> Result<(), anyhow::Error> {
+    if self.cancelled.load(Ordering::SeqCst) {
+      bail!("Job {} was cancelled", self.id)
+    }
+    Ok(())
+  }
+
+  // Jobs that work through several files report progress for one item at a time
+  pub fn set_current_item(&self, item: String) {
+    if let Ok(mut current_item) = self.current_item.lock() {
+      *current_item = Some(item);
+    }
+    self.last_reported.store(0, Ordering::Relaxed);
+  }
+
+  pub fn report_progress(&self, bytes_done: u64, total_bytes: u64) {
+    let step = (total_bytes / PROGRESS_STEPS).max(1);
+    let last_reported = self.last_reported.load(Ordering::Relaxed);
+    if bytes_done == total_bytes || bytes_done < last_reported || bytes_done - last_reported >= step {
+      self.last_reported.store(bytes_done, Ordering::Relaxed);
+      (self.on_progress)(JobProgress {
+        id: self.id,
+        bytes_done,
+        total_bytes,
+        current_item: self.current_item.lock().ok().and_then(|current_item| current_item.clone())
+      });
+    }
+  }
+}
+
+// Reports progress for every byte read through it and stops reading once the job is cancelled
+pub struct JobReader<R: Read> {
+  inner: R,
+  job: Arc<Job>,
+  bytes_done: u64,
+  total_bytes: u64
+}
+
+impl<R: Read> JobReader<R> {
+  pub fn new(inner: R, job: Arc<Job>, total_bytes: u64) -> Self {
+    Self {
+      inner,
+      job,
+      bytes_done: 0,
+      total_bytes
+    }
+  }
+}
+
+impl<R: Read> Read for JobReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.job.check_cancelled()
+      .map_err(io::Error::other)?;
+    let bytes_read = self.inner.read(buf)?;
+    self.bytes_done += bytes_read as u64;
+    self.job.report_progress(self.bytes_done, self.total_bytes);
+    Ok(bytes_read)
+  }
+}
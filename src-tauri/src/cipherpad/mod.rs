@@ -1,25 +1,61 @@
-use std::{collections::{HashMap, HashSet}, cell::RefCell, rc::Rc, io::{Read, Write}};
+use std::{collections::{HashMap, HashSet}, fmt, io::{Cursor, Read, SeekFrom, Write}, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
 use anyhow::{bail, Context};
+use file_format::FileFormat;
+use rusqlite::{blob::Blob, params, DatabaseName, Transaction};
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
-use tokio::{fs::{File, self}, io::{BufReader, AsyncReadExt, BufWriter, AsyncWriteExt}};
+use tokio::{fs::{File, self}, io::{AsyncReadExt, AsyncSeekExt}};
 use uuid::Uuid;
 
-use crate::cipherpad::utils::read_exact_chunk;
+use self::{db::{SqlParamsBuilder, value_from_sql}, utils::{create_temp_file, chunk_size_for_file, copy_with_checksum, millis_since_epoch, now_millis, to_hex, is_compressed_media_type, media_kind, MediaKind, CHUNK_SIZE}, crypto::{KEY_SIZE, SALT_SIZE}, image_metadata::can_strip_metadata, jobs::JobReader, ordering::{key_between, sibling_sort_key}};
 
-use self::{db::{DatabasePool, SqlParamsBuilder, value_from_sql}, utils::{create_temp_file, CHUNK_SIZE}, crypto::{KEY_SIZE, SALT_SIZE}};
+pub use self::{archive::{export_archive, import_archive, ArchiveReport}, backup::{create_backup, run_scheduled_backups, BackupReport}, db::DatabasePool, export::{export_subtree, ExportReport}, import::{import_directory, ImportReport}, jobs::Job, stream::{BlobOptions, PadReader, PadWriter, WrittenBlob}, utils::MEDIA_SNIFF_SIZE, verify::{verify_vault, VerifyReport}};
 
+mod archive;
+mod backup;
 mod crypto;
 mod db;
+mod export;
+mod image_metadata;
+mod import;
+mod jobs;
+mod ordering;
+mod stream;
+mod thumbnail;
+mod tree;
 mod utils;
+mod verify;
 
 const MAX_BLOB_SIZE: usize = 1_000_000_000;
+const RECOVERED_PAD_ID: Uuid = Uuid::from_u128(0x5245_434f_5645_5245_4400_0000_0000_0001); // Only exists in memory, never in the node table
+const RECOVERED_PAD_NAME: &str = "Recovered";
+const KEY_CHECK_PLAINTEXT: &[u8] = b"cipherpad-key-check";
 
 pub struct Cipherpad {
   pub pool: Option<DatabasePool>,
-  pub node_tree: NodeTree,
+  pub node_tree: Option<NodeTree>, // Loaded on unlock and kept up to date by the pad commands
   pub pad_map: PadMap,
-  pub master_key: Option<[u8; KEY_SIZE]>
+  pub master_key: Option<[u8; KEY_SIZE]>,
+  pub jobs: HashMap<Uuid, Arc<Job>>,
+  pub quarantined_pads: Vec<QuarantinedPad>
+}
+
+#[derive(Serialize)]
+pub struct TrashedPad {
+  id: Uuid,
+  #[serde(rename = "parentId")]
+  parent_id: Option<Uuid>,
+  metadata: String,
+  #[serde(rename = "deletedAt")]
+  deleted_at: u64
+}
+
+#[derive(Clone, Serialize)]
+pub struct QuarantinedPad {
+  id: Uuid,
+  #[serde(rename = "parentId")]
+  parent_id: Option<Uuid>,
+  error: String
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -69,47 +105,78 @@ pub struct EncryptedPad {
   #[serde(rename = "parentId")]
   pub parent_id: Option<Uuid>,
   #[serde(rename = "metadata")]
-  pub metadata: String
+  pub metadata: String,
+  #[serde(rename = "sortKey", default, skip_serializing_if = "Option::is_none")]
+  pub sort_key: Option<String>,
+  #[serde(default)]
+  pub revision: u64
+}
+
+#[derive(Serialize)]
+pub struct DecryptedPad {
+  #[serde(rename = "padData")]
+  pad_data: String,
+  revision: u64
+}
+
+// Returned when a pad is saved from a revision other than its current one
+#[derive(Debug)]
+pub struct RevisionConflict {
+  pub current_revision: u64
+}
+
+impl fmt::Display for RevisionConflict {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Pad was saved elsewhere, its current revision is {}", self.current_revision)
+  }
 }
 
+impl std::error::Error for RevisionConflict {}
+
 impl EncryptedPad {
-  pub fn new(id: Uuid, parent_id: Option<Uuid>, pad_metadata_encrypted: Vec<u8>, master_key: &[u8]) -> Result<Self, anyhow::Error> {
+  pub fn new(id: Uuid, parent_id: Option<Uuid>, pad_metadata_encrypted: Vec<u8>, sort_key: Option<String>, revision: u64, master_key: &[u8]) -> Result<Self, anyhow::Error> {
     let metadata = crypto::decrypt_as_string(&pad_metadata_encrypted, master_key)?;
     Ok(Self {
       id,
       parent_id,
-      metadata
+      metadata,
+      sort_key,
+      revision
     })
   }
 
 
-  async fn select_encrypted_data(self, pool: &DatabasePool) -> Result<Vec<u8>, anyhow::Error> {
+  // Milliseconds since the epoch, older pads may have stored it as a float
+  pub fn created_at(&self) -> Option<u64> {
+    let metadata: Value = serde_json::from_str(&self.metadata).ok()?;
+    let created_at = metadata.get("createdAt")?;
+    created_at.as_u64().or_else(|| created_at.as_f64().filter(|created_at| *created_at >= 0.0).map(|created_at| created_at as u64))
+  }
+
+  pub async fn decrypt_pad(self, master_key: &[u8], pool: &DatabasePool) -> Result<DecryptedPad, anyhow::Error> {
+    // The Recovered pad only exists in memory and has no text of its own
+    if self.id == RECOVERED_PAD_ID {
+      return Ok(DecryptedPad {
+        pad_data: serde_json::json!({ "text": "", "revisionHistory": [] }).to_string(),
+        revision: 0
+      });
+    }
     let data_select_result = pool.select_query_single(
-      "SELECT pad_data FROM NODE WHERE id = ?1",
+      "SELECT pad_data, revision FROM node WHERE id = ?1",
       SqlParamsBuilder::new()
         .add_param(self.id)
         .build(),
-        1
+        2
     ).await?;
-    let encrypted_data_vec = value_from_sql::<Vec<u8>>(data_select_result.get(0))?;
-    Ok(encrypted_data_vec)
+    let encrypted_data = value_from_sql::<Vec<u8>>(data_select_result.get(0))?;
+    Ok(DecryptedPad {
+      pad_data: String::from_utf8(crypto::decrypt_compressed(&encrypted_data, master_key)?)?,
+      revision: value_from_sql::<u64>(data_select_result.get(1))?
+    })
   }
 
   pub async fn decrypt_pad_data(self, master_key: &[u8], pool: &DatabasePool) -> Result<String, anyhow::Error> {
-
-    match crypto::decrypt_as_string(&self.select_encrypted_data(pool).await?, master_key) {
-      Ok(pad_data) => {
-        Ok(pad_data)
-      },
-      Err(err) => bail!("Error: {}", err)
-    }
-  }
-
-  pub async fn delete_node(self, pool: &DatabasePool) -> Result<(), anyhow::Error> {
-    pool.execute_query("DELETE FROM node WHERE id = ?1", 
-      SqlParamsBuilder::new().add_param(self.id).build()
-    ).await?;
-    Ok(())
+    Ok(self.decrypt_pad(master_key, pool).await?.pad_data)
   }
 
 
@@ -118,162 +185,370 @@ impl EncryptedPad {
     Ok(blob_pad_metadata)
   }
 
-  pub async fn clear_pad_data(self, pool: &DatabasePool) -> Result<(), anyhow::Error> {
-    pool.execute_query("UPDATE node SET pad_data = ZEROBLOB(0) WHERE id = ?1",
-    SqlParamsBuilder::new()
-      .add_param(self.id).build()
-    ).await?;
-    Ok(())
-  }
-
-  pub async fn encrypt_and_append_pad_data(self, file_writer: &mut BufWriter<File>, master_key: &[u8], data: &[u8]) -> Result<usize, anyhow::Error> {
-    let encrypted_data = crypto::encrypt(data, master_key)?;
-    let encrypted_data_len = encrypted_data.len();
-    file_writer.write(&encrypted_data).await?;
-    Ok(encrypted_data_len)
-  }
-
-  pub async fn encrypt_file_to_pad(self, pool: &DatabasePool, master_key: &[u8], file: &str) -> Result<(), anyhow::Error> {
-    self.clone().clear_pad_data(pool).await?;
+  // Blobs are stored once in blob_content, pads saved before that keep their blob in node.pad_data
+  async fn select_blob_location(&self, pool: &DatabasePool) -> Result<(&'static str, &'static str, i64), anyhow::Error> {
     let select_row_id_result = pool.select_query_single(
-      "SELECT rowid FROM node WHERE id = ?1",
+      "SELECT node.rowid, blob_content.rowid FROM node \
+      LEFT JOIN blob_content ON blob_content.content_hash = node.content_hash \
+      WHERE node.id = ?1",
       SqlParamsBuilder::new()
         .add_param(self.id)
         .build(),
-      1
+      2
     ).await?;
-    let row_id = value_from_sql::<i64>(select_row_id_result.get(0))?;
-    let file = File::open(file).await?;
-    let mut reader = BufReader::new(file);
-    let mut encrypted_chunk_sizes = Vec::<u8>::new();
-    let mut buffer = [0u8; CHUNK_SIZE];
-    let (temp_path, temp_file) = create_temp_file().await?;
-    let mut temp_file_writer = BufWriter::new(temp_file);
-    loop {
-      let bytes_read = reader.read(&mut buffer[..]).await?;
-      if bytes_read == 0 {
-        break;
-      }
-      let encrypted_data_len = self.clone().encrypt_and_append_pad_data(&mut temp_file_writer, master_key, &buffer[..bytes_read]).await?;
-      encrypted_chunk_sizes.extend(encrypted_data_len.to_be_bytes());
+    match value_from_sql::<Option<i64>>(select_row_id_result.get(1))? {
+      Some(content_row_id) => Ok(("blob_content", "content_data", content_row_id)),
+      None => Ok(("node", "pad_data", value_from_sql::<i64>(select_row_id_result.get(0))?))
     }
-    temp_file_writer.flush().await?;
-    let temp_file_metadata = fs::metadata(&temp_path).await?;
-    let encrypted_encrypted_chunk_sizes = crypto::encrypt(&encrypted_chunk_sizes, master_key)?;
+  }
+
+  pub async fn read_pad_data<F, R>(self, pool: &DatabasePool, master_key: &[u8], func: F) -> Result<R, anyhow::Error>
+  where
+    F: FnOnce(PadReader<Blob>) -> Result<R, anyhow::Error> + Send + 'static,
+    R: Send + 'static
+  {
+    let (table, column, row_id) = self.select_blob_location(pool).await?;
+    let master_key = master_key.to_vec();
+    let legacy_data_offset = match table {
+      "node" => self.clone().get_blob_pad_metadata()?.encrypted_data_offset,
+      _ => None
+    };
+    pool.open_blob(row_id, column, table, true, move |blob| {
+      let pad_reader = PadReader::new(blob, &master_key, legacy_data_offset)?;
+      func(pad_reader)
+    }).await
+  }
+
+  pub async fn write_pad_data<F>(self, pool: &DatabasePool, master_key: &[u8], options: BlobOptions, job: Arc<Job>, func: F) -> Result<(), anyhow::Error>
+  where
+    F: FnOnce(&mut PadWriter<std::io::BufWriter<std::fs::File>>) -> Result<(), anyhow::Error> + Send + 'static
+  {
+    let (temp_path, written_blob, _) = encrypt_to_temp_file(master_key, options, job.clone(), func).await?;
+    let save_result = self.save_pad_data(pool, master_key, written_blob, temp_path.clone(), job).await;
+    fs::remove_file(&temp_path).await?;
+    save_result
+  }
+
+  async fn save_pad_data(self, pool: &DatabasePool, master_key: &[u8], written_blob: WrittenBlob, temp_path: PathBuf, job: Arc<Job>) -> Result<(), anyhow::Error> {
     let mut blob_pad_metadata = self.clone().get_blob_pad_metadata()?;
-    blob_pad_metadata.encrypted_data_offset = encrypted_encrypted_chunk_sizes.len();
+    blob_pad_metadata.record_written_blob(&written_blob);
+    let now = now_millis();
+    blob_pad_metadata.created_at = select_created_at(pool, master_key, self.id).await?.unwrap_or(now).into();
+    blob_pad_metadata.last_modified_at = now.into();
     let blob_pad_metadata = serde_json::to_string(&blob_pad_metadata)?;
     let encrypted_blob_pad_metadata = crypto::encrypt(blob_pad_metadata.as_bytes(), master_key)?;
 
-    if encrypted_encrypted_chunk_sizes.len() + temp_file_metadata.len() as usize >= MAX_BLOB_SIZE {
-      bail!("File is too large to store into Cipherpad.")
-    }
+    let id = self.id;
+    pool.transaction(move |transaction| {
+      store_blob_content(transaction, &written_blob, &temp_path, &job)?;
+      transaction.execute("UPDATE node \
+        SET pad_metadata = ?1, \
+        pad_data = ZEROBLOB(0), \
+        content_hash = ?2, \
+        thumbnail = NULL, \
+        revision = revision + 1 \
+        WHERE id = ?3",
+        params![encrypted_blob_pad_metadata, written_blob.content_hash, id]
+      )?;
+      Ok(())
+    }).await
+  }
 
-    pool.execute_query("UPDATE node \
-      SET pad_metadata = ?1, \
-      pad_data = ZEROBLOB(?2)
-      WHERE id = ?3",
-      SqlParamsBuilder::new()
-      .add_param(encrypted_blob_pad_metadata)
-      .add_param(encrypted_encrypted_chunk_sizes.len() + temp_file_metadata.len() as usize)
-      .add_param(self.id)
-      .build()
-    ).await?;
+  pub async fn encrypt_file_to_pad(mut self, pool: &DatabasePool, master_key: &[u8], file: &str, import_options: ImportOptions, job: Arc<Job>) -> Result<Vec<String>, anyhow::Error> {
+    let mut blob_pad_metadata = self.clone().get_blob_pad_metadata()?;
+    let strip_metadata = match import_options.strip_metadata {
+      Some(strip_metadata) => strip_metadata,
+      None => VaultSettings::load(pool).await?.strip_image_metadata
+    };
+    let (file_reader, total_bytes, stripped_fields) = read_file_for_import(file, &mut blob_pad_metadata, strip_metadata).await?;
+    let media_type = blob_pad_metadata.media_type.clone().unwrap_or_default();
+    self.metadata = serde_json::to_string(&blob_pad_metadata)?;
 
-    pool.open_blob(row_id, "pad_data", "node", false, move |blob| {
-      let mut blob_writer = std::io::BufWriter::new(blob);
-      blob_writer.write(&encrypted_encrypted_chunk_sizes)?;
-      let std_fs_tmp_file = std::fs::File::open(&temp_path)?;
-      let mut file_reader = std::io::BufReader::new(std_fs_tmp_file);
-
-      let mut buffer = [0u8; CHUNK_SIZE];
-      loop {
-        let bytes_read = file_reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-          break;
-        }
-        blob_writer.write(&buffer[..bytes_read])?;
-      }
-      blob_writer.flush()?;
-      std::fs::remove_file(&temp_path)?;
+    let options = BlobOptions {
+      chunk_size: import_options.chunk_size.unwrap_or(chunk_size_for_file(total_bytes)),
+      compress: import_options.compress.unwrap_or(true) && !is_compressed_media_type(&media_type)
+    };
+    let reader_job = job.clone();
+    self.clone().write_pad_data(pool, master_key, options, job, move |pad_writer| {
+      let mut file_reader = JobReader::new(file_reader, reader_job, total_bytes);
+      std::io::copy(&mut file_reader, pad_writer)?;
       Ok(())
     }).await?;
-    
+
+    // A pad without a thumbnail is still usable, so images that fail to decode are imported without one
+    if let MediaKind::Image = media_kind(&media_type) {
+      let file_path = file.to_string();
+      if let Ok(Ok(thumbnail)) = tokio::task::spawn_blocking(move || thumbnail::generate_thumbnail(&file_path)).await {
+        self.save_thumbnail(pool, master_key, &thumbnail).await?;
+      }
+    }
+    Ok(stripped_fields)
+  }
+
+  async fn save_thumbnail(self, pool: &DatabasePool, master_key: &[u8], thumbnail: &[u8]) -> Result<(), anyhow::Error> {
+    let encrypted_thumbnail = crypto::encrypt(thumbnail, master_key)?;
+    pool.execute_query("UPDATE node SET thumbnail = ?1 WHERE id = ?2",
+      SqlParamsBuilder::new()
+        .add_param(encrypted_thumbnail)
+        .add_param(self.id)
+        .build()
+    ).await?;
     Ok(())
   }
 
-  pub async fn decrypt_pad_to_file(self, pool: &DatabasePool, master_key: &[u8], file_to_create: &str) -> Result<(), anyhow::Error> {
-    let select_row_id_result = pool.select_query_single(
-      "SELECT rowid FROM node WHERE id = ?1",
+  pub async fn get_thumbnail(self, pool: &DatabasePool, master_key: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let thumbnail_select_result = pool.select_query_single(
+      "SELECT thumbnail FROM node WHERE id = ?1",
       SqlParamsBuilder::new()
         .add_param(self.id)
         .build(),
-        1).await?;
-    let master_key = master_key.to_vec();
+      1
+    ).await?;
+    match value_from_sql::<Option<Vec<u8>>>(thumbnail_select_result.get(0))? {
+      Some(encrypted_thumbnail) => Ok(Some(crypto::decrypt(&encrypted_thumbnail, master_key)?)),
+      None => Ok(None)
+    }
+  }
+
+  pub async fn decrypt_pad_to_file(self, pool: &DatabasePool, master_key: &[u8], file_to_create: &str, job: Arc<Job>) -> Result<(), anyhow::Error> {
     let file_to_create = file_to_create.to_string();
-    let row_id = value_from_sql::<i64>(select_row_id_result.get(0))?;
-    let blob_pad_metadata = self.clone().get_blob_pad_metadata()?;
-    pool.open_blob(row_id, "pad_data", "node", true, move |blob| {
-      let mut reader = std::io::BufReader::new(blob);
-      let mut encrypted_encrypted_chunk_sizes = vec![0u8; blob_pad_metadata.encrypted_data_offset];
-      reader.read(&mut encrypted_encrypted_chunk_sizes)?;
-      let encrypted_chunk_sizes_bytes = crypto::decrypt(&encrypted_encrypted_chunk_sizes, &master_key)?;
-      let mut encrpyted_chunk_sizes = Vec::<usize>::new();
-      for chunk in encrypted_chunk_sizes_bytes.chunks_exact(8) {
-        let chunk_size = usize::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7]]);
-        encrpyted_chunk_sizes.push(chunk_size);
-      }
-      let file = std::fs::File::create(file_to_create)?;
+    let expected_sha256 = self.clone().get_blob_pad_metadata()?.sha256;
+    self.read_pad_data(pool, master_key, move |pad_reader| {
+      let total_bytes = pad_reader.len();
+      let file = std::fs::File::create(&file_to_create)?;
       let mut file_writer = std::io::BufWriter::new(file);
-      for encrypted_chunk_size in encrpyted_chunk_sizes {
-        let mut encrypted_chunk = vec![0u8; encrypted_chunk_size];
-        read_exact_chunk(&mut reader, &mut encrypted_chunk)?;
-        let chunk = crypto::decrypt(&encrypted_chunk, &master_key)?;
-        file_writer.write(&chunk)?;
+      let mut pad_reader = JobReader::new(pad_reader, job, total_bytes);
+      let copy_result = copy_with_checksum(&mut pad_reader, &mut file_writer)
+        .and_then(|sha256| match expected_sha256 {
+          Some(expected_sha256) if expected_sha256 != sha256 => {
+            bail!("Exported file has SHA-256 {} but {} was recorded when it was imported", sha256, expected_sha256)
+          },
+          _ => Ok(())
+        });
+      if let Err(err) = copy_result {
+        drop(file_writer);
+        std::fs::remove_file(&file_to_create)?;
+        return Err(err);
       }
       Ok(())
-    }).await?;
-    Ok(())
+    }).await
   }
 
-  pub async fn decrypt_pad_to_blob(self, pool: &DatabasePool, master_key: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
-    let select_row_id_result = pool.select_query_single(
-      "SELECT rowid FROM node WHERE id = ?1",
-      SqlParamsBuilder::new()
-        .add_param(self.id)
-        .build(),
-        1).await?;
-    let master_key = master_key.to_vec();
-    let row_id = value_from_sql::<i64>(select_row_id_result.get(0))?;
-    let blob_pad_metadata = self.clone().get_blob_pad_metadata()?;
-    let blob = pool.open_blob(row_id, "pad_data", "node", true, move |blob| {
-      let mut reader = std::io::BufReader::new(blob);
-      let mut encrypted_encrypted_chunk_sizes = vec![0u8; blob_pad_metadata.encrypted_data_offset];
-      reader.read(&mut encrypted_encrypted_chunk_sizes)?;
-      let encrypted_chunk_sizes_bytes = crypto::decrypt(&encrypted_encrypted_chunk_sizes, &master_key)?;
-      let mut encrpyted_chunk_sizes = Vec::<usize>::new();
-      for chunk in encrypted_chunk_sizes_bytes.chunks_exact(8) {
-        let chunk_size = usize::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7]]);
-        encrpyted_chunk_sizes.push(chunk_size);
-      }
-      let mut blob = Vec::<u8>::new();
-      for encrypted_chunk_size in encrpyted_chunk_sizes {
-        let mut encrypted_chunk = vec![0u8; encrypted_chunk_size];
-        read_exact_chunk(&mut reader, &mut encrypted_chunk)?;
-        let chunk = crypto::decrypt(&encrypted_chunk, &master_key)?;
-        blob.extend(chunk);
-      }
-      Ok(blob)
-    }).await?;
-    Ok(blob)
+  // Pads imported before checksums were recorded are hashed from their decrypted data
+  pub async fn get_checksum(self, pool: &DatabasePool, master_key: &[u8]) -> Result<String, anyhow::Error> {
+    if let Some(sha256) = self.clone().get_blob_pad_metadata()?.sha256 {
+      return Ok(sha256);
+    }
+    self.read_pad_data(pool, master_key, move |mut pad_reader| {
+      copy_with_checksum(&mut pad_reader, &mut std::io::sink())
+    }).await
+  }
+
+  pub async fn decrypt_pad_to_blob(self, pool: &DatabasePool, master_key: &[u8]) -> Result<(Vec<u8>, String), anyhow::Error> {
+    let media_type = self.clone().get_media_type()?;
+    self.read_pad_data(pool, master_key, move |mut pad_reader| {
+      let mut blob = Vec::with_capacity(pad_reader.len() as usize);
+      pad_reader.read_to_end(&mut blob)?;
+      let media_type = match media_type {
+        Some(media_type) => media_type,
+        None => FileFormat::from_bytes(&blob[..blob.len().min(MEDIA_SNIFF_SIZE as usize)]).media_type().to_string()
+      };
+      Ok((blob, media_type))
+    }).await
+  }
+
+  // Detected once at import, pads imported before that return None and have to be sniffed
+  pub fn get_media_type(self) -> Result<Option<String>, anyhow::Error> {
+    Ok(self.get_blob_pad_metadata()?.media_type)
+  }
+
+}
+
+// Sniffs the file and fills in its details, stripping image metadata in memory when asked.
+// Returns a reader over the data to store, its length and the names of any stripped metadata fields.
+async fn read_file_for_import(file_path: &str, blob_pad_metadata: &mut BlobPadMetadata, strip_metadata: bool) -> Result<(Box<dyn Read + Send>, u64, Vec<String>), anyhow::Error> {
+  let mut file = File::open(file_path).await?;
+  let mut head = Vec::new();
+  (&mut file).take(MEDIA_SNIFF_SIZE).read_to_end(&mut head).await?;
+  file.seek(SeekFrom::Start(0)).await?;
+  let file_format = FileFormat::from_bytes(&head);
+  let media_type = file_format.media_type().to_string();
+
+  blob_pad_metadata.source_modified_at = file.metadata().await?.modified().ok().and_then(millis_since_epoch);
+  blob_pad_metadata.media_type = Some(media_type.clone());
+  blob_pad_metadata.extension = Some(file_format.extension().to_string());
+  blob_pad_metadata.kind = Some(media_kind(&media_type));
+
+  // Images are rewritten in memory so the original with its metadata never reaches the vault
+  if strip_metadata && can_strip_metadata(&media_type) {
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).await?;
+    let (stripped_data, stripped_fields) = tokio::task::spawn_blocking(move || image_metadata::strip_metadata(data, &media_type)).await??;
+    blob_pad_metadata.stripped_metadata = Some(stripped_fields.clone());
+    let total_bytes = stripped_data.len() as u64;
+    Ok((Box::new(Cursor::new(stripped_data)), total_bytes, stripped_fields))
+  } else {
+    let total_bytes = file.metadata().await?.len();
+    Ok((Box::new(std::io::BufReader::new(file.into_std().await)), total_bytes, Vec::new()))
+  }
+}
+
+// Encrypts everything func writes into a temp file, which the caller has to remove once it is stored
+async fn encrypt_to_temp_file<F, R>(master_key: &[u8], options: BlobOptions, job: Arc<Job>, func: F) -> Result<(PathBuf, WrittenBlob, R), anyhow::Error>
+where
+  F: FnOnce(&mut PadWriter<std::io::BufWriter<std::fs::File>>) -> Result<R, anyhow::Error> + Send + 'static,
+  R: Send + 'static
+{
+  let (temp_path, temp_file) = create_temp_file().await?;
+  let temp_file = temp_file.into_std().await;
+  let writer_master_key = master_key.to_vec();
+  let writer_temp_path = temp_path.clone();
+  let write_result = tokio::task::spawn_blocking(move || {
+    let mut pad_writer = PadWriter::new(std::io::BufWriter::new(temp_file), &writer_master_key, options)?;
+    let result = func(&mut pad_writer)?;
+    job.check_cancelled()?;
+    let (_, written_blob) = pad_writer.finish()?;
+    blob_len(&written_blob, &writer_temp_path)?;
+    Ok::<(WrittenBlob, R), anyhow::Error>((written_blob, result))
+  }).await?;
+  match write_result {
+    Ok((written_blob, result)) => Ok((temp_path, written_blob, result)),
+    Err(err) => {
+      fs::remove_file(&temp_path).await?;
+      Err(err)
+    }
+  }
+}
+
+fn blob_len(written_blob: &WrittenBlob, temp_path: &Path) -> Result<usize, anyhow::Error> {
+  let blob_len = written_blob.header.len() + std::fs::metadata(temp_path)?.len() as usize;
+  if blob_len >= MAX_BLOB_SIZE {
+    bail!("File is too large to store into Cipherpad.")
+  }
+  Ok(blob_len)
+}
+
+// Loading the pad map cannot tell a wrong password apart from a vault whose pads are all in the trash,
+// so the key is checked against a stored value. Vaults without one are checked against any pad, trashed ones included.
+async fn check_master_key(pool: &DatabasePool, key_check: Option<Vec<u8>>, master_key: &[u8]) -> Result<(), anyhow::Error> {
+  if let Some(key_check) = key_check {
+    if !matches!(crypto::decrypt(&key_check, master_key), Ok(plaintext) if plaintext == KEY_CHECK_PLAINTEXT) {
+      bail!("Incorrect password");
+    }
+    return Ok(());
+  }
+  let nodes = pool.select_query("SELECT pad_metadata FROM node", vec![], 1).await?;
+  let key_matches = nodes.is_empty() || nodes.iter().any(|node| {
+    value_from_sql::<Vec<u8>>(node.get(0)).is_ok_and(|pad_metadata| crypto::decrypt(&pad_metadata, master_key).is_ok())
+  });
+  if !key_matches {
+    bail!("Incorrect password");
+  }
+  pool.execute_query("UPDATE cipherpad SET key_check = ?1 WHERE id = 1",
+    SqlParamsBuilder::new().add_param(crypto::encrypt(KEY_CHECK_PLAINTEXT, master_key)?).build()
+  ).await?;
+  Ok(())
+}
+
+// Only the top of a deleted subtree is marked, its descendants are hidden along with it
+async fn trash_node(pool: &DatabasePool, id: Uuid, deleted_at: u64) -> Result<(), anyhow::Error> {
+  pool.execute_query("UPDATE node SET deleted_at = ?1 WHERE id = ?2",
+    SqlParamsBuilder::new().add_param(deleted_at).add_param(id).build()
+  ).await?;
+  Ok(())
+}
+
+// Overwrites a blob in place. Only usable on columns that are not part of an index,
+// SQLite refuses to open indexed columns for writing.
+fn zero_blob(transaction: &Transaction, table: &str, column: &str, row_id: i64) -> Result<(), anyhow::Error> {
+  let mut blob = transaction.blob_open(DatabaseName::Main, table, column, row_id, false)?;
+  let zeros = [0u8; CHUNK_SIZE];
+  let mut remaining = blob.len();
+  while remaining > 0 {
+    let zeros_len = remaining.min(CHUNK_SIZE);
+    blob.write_all(&zeros[..zeros_len])?;
+    remaining -= zeros_len;
+  }
+  Ok(())
+}
+
+// Overwrites the ciphertext of trashed subtrees deleted before deleted_before in place before deleting them.
+// Shared blob content is only overwritten once no pad outside the deleted subtrees refers to it.
+fn delete_trashed_nodes(transaction: &Transaction, deleted_before: i64) -> Result<(), anyhow::Error> {
+  const DELETED_NODES: &str = "WITH RECURSIVE deleted(id) AS ( \
+      SELECT id FROM node WHERE deleted_at IS NOT NULL AND deleted_at < ?1 \
+      UNION \
+      SELECT node.id FROM node JOIN deleted ON node.parent_id = deleted.id \
+    ) ";
+
+  let mut statement = transaction.prepare(&format!("{} SELECT rowid, thumbnail IS NOT NULL FROM node WHERE id IN deleted", DELETED_NODES))?;
+  let node_rows = statement.query_map(params![deleted_before], |row| Ok((row.get::<usize, i64>(0)?, row.get::<usize, bool>(1)?)))?
+    .collect::<Result<Vec<(i64, bool)>, rusqlite::Error>>()?;
+  for (row_id, has_thumbnail) in node_rows {
+    // pad_metadata is part of node_tree_index, so it is overwritten through an update instead of a blob handle
+    transaction.execute("UPDATE node SET pad_metadata = ZEROBLOB(length(pad_metadata)) WHERE rowid = ?1", params![row_id])?;
+    zero_blob(transaction, "node", "pad_data", row_id)?;
+    if has_thumbnail {
+      zero_blob(transaction, "node", "thumbnail", row_id)?;
+    }
+  }
+
+  let mut statement = transaction.prepare(&format!("{} SELECT rowid FROM blob_content \
+    WHERE content_hash IN (SELECT content_hash FROM node WHERE id IN deleted) \
+    AND content_hash NOT IN (SELECT content_hash FROM node WHERE id NOT IN deleted AND content_hash IS NOT NULL)", DELETED_NODES))?;
+  let content_rows = statement.query_map(params![deleted_before], |row| row.get::<usize, i64>(0))?
+    .collect::<Result<Vec<i64>, rusqlite::Error>>()?;
+  for row_id in content_rows {
+    zero_blob(transaction, "blob_content", "content_data", row_id)?;
+  }
+
+  // Deleting the top of a trashed subtree removes the rest of it through the ON DELETE CASCADE
+  transaction.execute("DELETE FROM node WHERE deleted_at IS NOT NULL AND deleted_at < ?1", params![deleted_before])?;
+  Ok(())
+}
+
+// Copies an encrypted temp file into blob_content unless identical content is already stored,
+// in which case the pad only needs to reference it
+fn store_blob_content(transaction: &Transaction, written_blob: &WrittenBlob, temp_path: &Path, job: &Job) -> Result<(), anyhow::Error> {
+  let content_exists = transaction.query_row(
+    "SELECT EXISTS(SELECT 1 FROM blob_content WHERE content_hash = ?1)",
+    params![written_blob.content_hash],
+    |row| row.get::<usize, bool>(0)
+  )?;
+  if content_exists {
+    return Ok(());
   }
 
+  transaction.execute(
+    "INSERT INTO blob_content (content_hash, ref_count, content_data) VALUES (?1, 0, ZEROBLOB(?2))",
+    params![written_blob.content_hash, blob_len(written_blob, temp_path)?]
+  )?;
+  let content_row_id = transaction.last_insert_rowid();
+
+  let blob = transaction.blob_open(DatabaseName::Main, "blob_content", "content_data", content_row_id, false)?;
+  let mut blob_writer = std::io::BufWriter::new(blob);
+  blob_writer.write_all(&written_blob.header)?;
+  let std_fs_tmp_file = std::fs::File::open(temp_path)?;
+  let mut file_reader = std::io::BufReader::new(std_fs_tmp_file);
+
+  let mut buffer = [0u8; CHUNK_SIZE];
+  loop {
+    job.check_cancelled()?;
+    let bytes_read = file_reader.read(&mut buffer)?;
+    if bytes_read == 0 {
+      break;
+    }
+    blob_writer.write_all(&buffer[..bytes_read])?;
+  }
+  blob_writer.flush()?;
+  Ok(())
 }
 
 #[derive(Clone, Deserialize)]
 pub struct PadNode {
   id: Uuid,
-  pad: Pad
+  pad: Pad,
+  revision: u64 // Revision the pad was loaded at, saving fails if it has changed since
 }
 
 #[derive(Clone, Deserialize)]
@@ -297,55 +572,155 @@ pub struct BlobPadMetadata {
   last_modified_at: Value,
   #[serde(rename = "fileName")]
   file_name: String,
-  #[serde(rename = "encryptedDataOffset")]
-  encrypted_data_offset: usize
+  #[serde(rename = "encryptedDataOffset", default, skip_serializing_if = "Option::is_none")]
+  encrypted_data_offset: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  sha256: Option<String>,
+  #[serde(rename = "originalSize", default, skip_serializing_if = "Option::is_none")]
+  original_size: Option<u64>,
+  #[serde(rename = "sourceModifiedAt", default, skip_serializing_if = "Option::is_none")]
+  source_modified_at: Option<u64>,
+  #[serde(rename = "mediaType", default, skip_serializing_if = "Option::is_none")]
+  media_type: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  extension: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  kind: Option<MediaKind>,
+  #[serde(rename = "strippedMetadata", default, skip_serializing_if = "Option::is_none")]
+  stripped_metadata: Option<Vec<String>>
+}
+
+impl BlobPadMetadata {
+  fn record_written_blob(&mut self, written_blob: &WrittenBlob) {
+    self.encrypted_data_offset = None;
+    self.sha256 = Some(to_hex(&written_blob.checksum));
+    self.original_size = Some(written_blob.plaintext_len);
+  }
+}
+
+// Settings for importing a single file, anything left unset falls back to the vault or file defaults
+#[derive(Clone, Copy, Default, Deserialize)]
+pub struct ImportOptions {
+  #[serde(rename = "chunkSize")]
+  pub chunk_size: Option<usize>,
+  pub compress: Option<bool>,
+  #[serde(rename = "stripMetadata")]
+  pub strip_metadata: Option<bool>
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VaultSettings {
+  #[serde(rename = "stripImageMetadata")]
+  pub strip_image_metadata: bool,
+  #[serde(rename = "backupDirectory")]
+  pub backup_directory: Option<String>,
+  #[serde(rename = "backupIntervalHours")]
+  pub backup_interval_hours: Option<u32>,
+  #[serde(rename = "backupKeepDaily")]
+  pub backup_keep_daily: u32,
+  #[serde(rename = "backupKeepWeekly")]
+  pub backup_keep_weekly: u32,
+  #[serde(rename = "trashRetentionDays")]
+  pub trash_retention_days: Option<u32>
+}
+
+impl VaultSettings {
+  pub async fn load(pool: &DatabasePool) -> Result<Self, anyhow::Error> {
+    Self::load_if_created(pool).await?.context("Vault settings not found")
+  }
+
+  // None until the settings row is created on the first unlock
+  pub async fn load_if_created(pool: &DatabasePool) -> Result<Option<Self>, anyhow::Error> {
+    let settings_select_results = pool.select_query(
+      "SELECT strip_image_metadata, backup_directory, backup_interval_hours, backup_keep_daily, backup_keep_weekly, trash_retention_days \
+      FROM cipherpad WHERE id = 1",
+      SqlParamsBuilder::new().build(),
+      6
+    ).await?;
+    let settings_select_result = match settings_select_results.first() {
+      Some(settings_select_result) => settings_select_result,
+      None => return Ok(None)
+    };
+    Ok(Some(Self {
+      strip_image_metadata: value_from_sql::<bool>(settings_select_result.get(0))?,
+      backup_directory: value_from_sql::<Option<String>>(settings_select_result.get(1))?,
+      backup_interval_hours: value_from_sql::<Option<u32>>(settings_select_result.get(2))?,
+      backup_keep_daily: value_from_sql::<u32>(settings_select_result.get(3))?,
+      backup_keep_weekly: value_from_sql::<u32>(settings_select_result.get(4))?,
+      trash_retention_days: value_from_sql::<Option<u32>>(settings_select_result.get(5))?
+    }))
+  }
+
+  pub async fn save(self, pool: &DatabasePool) -> Result<(), anyhow::Error> {
+    pool.execute_query("UPDATE cipherpad \
+      SET strip_image_metadata = ?1, \
+      backup_directory = ?2, \
+      backup_interval_hours = ?3, \
+      backup_keep_daily = ?4, \
+      backup_keep_weekly = ?5, \
+      trash_retention_days = ?6 \
+      WHERE id = 1",
+      SqlParamsBuilder::new()
+        .add_param(self.strip_image_metadata)
+        .add_param(self.backup_directory)
+        .add_param(self.backup_interval_hours)
+        .add_param(self.backup_keep_daily)
+        .add_param(self.backup_keep_weekly)
+        .add_param(self.trash_retention_days)
+        .build()
+    ).await?;
+    Ok(())
+  }
 }
 
 impl PadNode {
   pub fn new(id: Uuid, pad: Pad) -> Self {
     Self {
       id,
-      pad
+      pad,
+      revision: 0
     }
   }
 
-  pub async fn encrypt_and_save(self, pool: &DatabasePool, master_key: &[u8]) -> Result<(), anyhow::Error> { 
-    let encrypted_pad_metadata = crypto::encrypt(self.pad.pad_metadata.as_bytes(), master_key)?;
-    let encrypted_pad_data = crypto::encrypt(self.pad.pad_data.as_bytes(), master_key)?;
-    if let Some(parent_id) = self.pad.parent_id {
-      pool.execute_query(
-        "UPDATE node \
-        SET parent_id = ?1, \
-        pad_metadata = ?2, \
-        pad_data = ?3 \
-        WHERE id = ?4;",
-        SqlParamsBuilder::new()
-        .add_param(parent_id)
-        .add_param(encrypted_pad_metadata)
-        .add_param(encrypted_pad_data)
-        .add_param(self.id)
-        .build()
-      ).await?;
-    } else { 
-
-      pool.execute_query(
-        "UPDATE node \
-        SET pad_metadata = ?1, \
-        pad_data = ?2 \
-        WHERE id = ?3;",
-        SqlParamsBuilder::new()
-        .add_param(encrypted_pad_metadata)
-        .add_param(encrypted_pad_data)
-        .add_param(self.id)
-        .build()
+  // Returns the new revision of the pad and the metadata saved with it
+  pub async fn encrypt_and_save(self, pool: &DatabasePool, master_key: &[u8]) -> Result<(u64, String), anyhow::Error> { 
+    let now = now_millis();
+    let created_at = select_created_at(pool, master_key, self.id).await?.unwrap_or(now);
+    let pad_metadata = stamp_metadata(&self.pad.pad_metadata, created_at, now)?;
+    let encrypted_pad_metadata = crypto::encrypt(pad_metadata.as_bytes(), master_key)?;
+    let encrypted_pad_data = crypto::encrypt_compressed(self.pad.pad_data.as_bytes(), master_key)?;
+    // Reparenting goes through Cipherpad::move_pads so it can be checked for cycles
+    let updated_rows = pool.execute_query(
+      "UPDATE node \
+      SET pad_metadata = ?1, \
+      pad_data = ?2, \
+      revision = revision + 1 \
+      WHERE id = ?3 AND revision = ?4;",
+      SqlParamsBuilder::new()
+      .add_param(encrypted_pad_metadata)
+      .add_param(encrypted_pad_data)
+      .add_param(self.id)
+      .add_param(self.revision)
+      .build()
+    ).await?;
+    if updated_rows == 0 {
+      let revision_select_result = pool.select_query_single("SELECT revision FROM node WHERE id = ?1",
+        SqlParamsBuilder::new().add_param(self.id).build(),
+        1
       ).await?;
+      return Err(RevisionConflict {
+        current_revision: value_from_sql::<u64>(revision_select_result.get(0))?
+      }.into());
     }
-    Ok(())
+    Ok((self.revision + 1, pad_metadata))
   }
   
-  pub async fn create_node(self, pool: &DatabasePool, master_key: &[u8]) -> Result<(), anyhow::Error> {
-    let encrypted_pad_metadata = crypto::encrypt(self.pad.pad_metadata.as_bytes(), master_key)?;
-    let encrypted_pad_data = crypto::encrypt(self.pad.pad_data.as_bytes(), master_key)?;
+  // Returns the metadata saved with the pad
+  pub async fn create_node(self, pool: &DatabasePool, master_key: &[u8]) -> Result<String, anyhow::Error> {
+    let now = now_millis();
+    let pad_metadata = stamp_metadata(&self.pad.pad_metadata, now, now)?;
+    let encrypted_pad_metadata = crypto::encrypt(pad_metadata.as_bytes(), master_key)?;
+    let encrypted_pad_data = crypto::encrypt_compressed(self.pad.pad_data.as_bytes(), master_key)?;
 
     pool.execute_query(
       "INSERT INTO node (id, parent_id, pad_metadata, pad_data) VALUES (?, ?, ?, ?)",
@@ -356,44 +731,34 @@ impl PadNode {
       .add_param(encrypted_pad_data)
       .build()
     ).await?;
-    Ok(())
+    Ok(pad_metadata)
   }
 }
 
-fn populate_node_map(
-  node_map: &mut HashMap<Uuid, Rc<RefCell<Node>>>,
-  node_db_map: &HashMap<Uuid, Uuid>,
-  root_nodes: &HashSet<Uuid>
-) {
-  let mut process_stack = Vec::new();
-  let mut postponed_stack = Vec::new();
-  
-  for &root_id in root_nodes {
-    if let Some(root_node) = node_map.get(&root_id) {
-      process_stack.push(root_node.clone());
-    }
-  }
-  
-  while let Some(current_node) = process_stack.pop() {
-    let current_node_id = current_node.borrow().id;
-  
-    postponed_stack.push(current_node.clone());
-  
-    for (&child_id, &_parent_id) in node_db_map.iter().filter(|&(_cid, &pid)| pid == current_node_id) {
-      if let Some(child_node) = node_map.get(&child_id) {
-        process_stack.push(child_node.clone());
-      }
-    }
-  }
-  
-  while let Some(child_node) = postponed_stack.pop() {
-    let child_node_id = child_node.borrow().id;
-    if let Some(&parent_id) = node_db_map.get(&child_node_id) {
-      if let Some(parent_node) = node_map.get(&parent_id) {
-        parent_node.borrow_mut().children.push(child_node.borrow().clone());
-      }
-    }
+// The creation time saved with the pad, rather than whatever the frontend sent along with it
+async fn select_created_at(pool: &DatabasePool, master_key: &[u8], id: Uuid) -> Result<Option<u64>, anyhow::Error> {
+  let metadata_select_result = pool.select_query_single(
+    "SELECT pad_metadata FROM node WHERE id = ?1",
+    SqlParamsBuilder::new()
+      .add_param(id)
+      .build(),
+    1
+  ).await?;
+  let pad_metadata_encrypted = value_from_sql::<Vec<u8>>(metadata_select_result.get(0))?;
+  Ok(EncryptedPad::new(id, None, pad_metadata_encrypted, None, 0, master_key)?.created_at())
+}
+
+// Timestamps are owned by the backend, whatever the frontend sent in the metadata is replaced
+fn stamp_metadata(pad_metadata: &str, created_at: u64, last_modified_at: u64) -> Result<String, anyhow::Error> {
+  let mut metadata: Value = serde_json::from_str(pad_metadata)?;
+  match metadata.as_object_mut() {
+    Some(metadata) => {
+      metadata.insert("createdAt".to_string(), created_at.into());
+      metadata.insert("lastModifiedAt".to_string(), last_modified_at.into());
+    },
+    None => bail!("Pad metadata is not an object")
   }
+  Ok(metadata.to_string())
 }
 
 impl Cipherpad {
@@ -401,8 +766,10 @@ impl Cipherpad {
     Self {
       pool: None, 
       pad_map: PadMap::new(),
-      node_tree: NodeTree::new(),
-      master_key: None
+      node_tree: None,
+      master_key: None,
+      jobs: HashMap::new(),
+      quarantined_pads: Vec::new()
     }
   }
 
@@ -414,8 +781,10 @@ impl Cipherpad {
     Ok(Self {
       pool: Some(pool),
       pad_map: PadMap::new(),
-      node_tree: NodeTree::new(),
-      master_key: None
+      node_tree: None,
+      master_key: None,
+      jobs: HashMap::new(),
+      quarantined_pads: Vec::new()
     })
   }
 
@@ -437,6 +806,70 @@ impl Cipherpad {
           master_key_salt BLOB
         );", vec![]
       ).await?;
+      pool.execute_query(
+        "CREATE TABLE IF NOT EXISTS blob_content ( \
+          content_hash BLOB PRIMARY KEY, \
+          ref_count INTEGER NOT NULL, \
+          content_data BLOB NOT NULL \
+        );", vec![]
+      ).await?;
+      pool.add_column_if_not_exists("node", "content_hash", "BLOB").await?;
+      pool.add_column_if_not_exists("node", "thumbnail", "BLOB").await?;
+      pool.add_column_if_not_exists("node", "sort_key", "TEXT").await?;
+      pool.add_column_if_not_exists("node", "deleted_at", "INTEGER").await?;
+      pool.add_column_if_not_exists("node", "revision", "INTEGER NOT NULL DEFAULT 0").await?;
+      // Covers every column the pad map loads, so unlocking never reads the node rows and their pad_data overflow pages
+      pool.execute_query(
+        "CREATE INDEX IF NOT EXISTS node_tree_index ON node (parent_id, sort_key, id, revision, pad_metadata);", vec![]
+      ).await?;
+      pool.execute_query(
+        "CREATE INDEX IF NOT EXISTS node_trash_index ON node (deleted_at) WHERE deleted_at IS NOT NULL;", vec![]
+      ).await?;
+      pool.add_column_if_not_exists("cipherpad", "strip_image_metadata", "INTEGER NOT NULL DEFAULT 0").await?;
+      pool.add_column_if_not_exists("cipherpad", "backup_directory", "TEXT").await?;
+      pool.add_column_if_not_exists("cipherpad", "backup_interval_hours", "INTEGER").await?;
+      pool.add_column_if_not_exists("cipherpad", "backup_keep_daily", "INTEGER NOT NULL DEFAULT 7").await?;
+      pool.add_column_if_not_exists("cipherpad", "backup_keep_weekly", "INTEGER NOT NULL DEFAULT 4").await?;
+      pool.add_column_if_not_exists("cipherpad", "trash_retention_days", "INTEGER DEFAULT 30").await?;
+      pool.add_column_if_not_exists("cipherpad", "key_check", "BLOB").await?;
+      pool.execute_query(
+        "CREATE TRIGGER IF NOT EXISTS node_content_insert AFTER INSERT ON node \
+        WHEN NEW.content_hash IS NOT NULL \
+        BEGIN \
+          UPDATE blob_content SET ref_count = ref_count + 1 WHERE content_hash = NEW.content_hash; \
+        END;", vec![]
+      ).await?;
+      pool.execute_query(
+        "CREATE TRIGGER IF NOT EXISTS node_content_update AFTER UPDATE OF content_hash ON node \
+        WHEN OLD.content_hash IS NOT NEW.content_hash \
+        BEGIN \
+          UPDATE blob_content SET ref_count = ref_count + 1 WHERE content_hash = NEW.content_hash; \
+          UPDATE blob_content SET ref_count = ref_count - 1 WHERE content_hash = OLD.content_hash; \
+          DELETE FROM blob_content WHERE content_hash = OLD.content_hash AND ref_count <= 0; \
+        END;", vec![]
+      ).await?;
+      pool.execute_query(
+        "CREATE TRIGGER IF NOT EXISTS node_content_delete AFTER DELETE ON node \
+        WHEN OLD.content_hash IS NOT NULL \
+        BEGIN \
+          UPDATE blob_content SET ref_count = ref_count - 1 WHERE content_hash = OLD.content_hash; \
+          DELETE FROM blob_content WHERE content_hash = OLD.content_hash AND ref_count <= 0; \
+        END;", vec![]
+      ).await?;
+      pool.execute_query(
+        "CREATE TRIGGER IF NOT EXISTS node_parent_cycle BEFORE UPDATE OF parent_id ON node \
+        WHEN NEW.parent_id IS NOT NULL \
+        BEGIN \
+          SELECT RAISE(ABORT, 'A pad cannot be moved into itself or one of its descendants') WHERE EXISTS ( \
+            WITH RECURSIVE ancestor(id) AS ( \
+              SELECT NEW.parent_id \
+              UNION \
+              SELECT node.parent_id FROM node JOIN ancestor ON node.id = ancestor.id WHERE node.parent_id IS NOT NULL \
+            ) \
+            SELECT 1 FROM ancestor WHERE id = NEW.id \
+          ); \
+        END;", vec![]
+      ).await?;
       pool.commit().await?;
     }
     Ok(()) 
@@ -444,15 +877,16 @@ impl Cipherpad {
 
   pub async fn derive_master_key(&mut self, password: &str) -> Result<(), anyhow::Error> {
     if let Some(ref pool) = self.pool {
-      let select_master_key_result = pool.select_query_single("SELECT master_key_salt FROM cipherpad WHERE id = 1;",
+      let select_master_key_result = pool.select_query_single("SELECT master_key_salt, key_check FROM cipherpad WHERE id = 1;",
         SqlParamsBuilder::new().build(),
-        1
+        2
       ).await;
       
-      let salt = match select_master_key_result {
+      let (salt, key_check) = match select_master_key_result {
         Ok(salt_result) => {
           let salt = value_from_sql::<[u8; SALT_SIZE]>(salt_result.get(0))?;
-          Ok::<[u8; SALT_SIZE], anyhow::Error>(salt)
+          let key_check = value_from_sql::<Option<Vec<u8>>>(salt_result.get(1))?;
+          Ok::<([u8; SALT_SIZE], Option<Vec<u8>>), anyhow::Error>((salt, key_check))
         },
         Err(_) => {
           let salt = crypto::generate_salt()?;
@@ -461,13 +895,14 @@ impl Cipherpad {
             .add_param(salt)
             .build()
           ).await?;
-          Ok(salt)
+          Ok((salt, None))
         }
       }?;
 
       let mut master_key = [0u8; KEY_SIZE];
 
       crypto::derive_key(password.as_bytes(), &salt, &mut master_key)?;
+      check_master_key(pool, key_check, &master_key).await?;
       self.master_key = Some(master_key);
       Ok(())
     }
@@ -476,53 +911,350 @@ impl Cipherpad {
     }
   }
 
-  pub async fn get_node_tree(&mut self) -> Result<NodeTree, anyhow::Error> {
+  async fn load_pad_map(&mut self) -> Result<(), anyhow::Error> {
     if let Some(ref pool) = self.pool {
       if let Some(master_key) = &self.master_key {
         let nodes = pool.select_query(
-          "SELECT id, parent_id, pad_metadata, pad_data FROM node",
+          "WITH RECURSIVE trashed(id) AS ( \
+            SELECT id FROM node WHERE deleted_at IS NOT NULL \
+            UNION \
+            SELECT node.id FROM node JOIN trashed ON node.parent_id = trashed.id \
+          ) \
+          SELECT id, parent_id, pad_metadata, sort_key, revision FROM node WHERE id NOT IN (SELECT id FROM trashed)",
           vec![],
-          3
+          5
         ).await?;
-        let mut node_tree = NodeTree::new();
-        let mut node_map = HashMap::new();
-        let mut node_db_map = HashMap::new();
-        let mut root_nodes = HashSet::new();
         self.pad_map.pads.clear();
+        self.quarantined_pads.clear();
         for node in nodes {
           let id = value_from_sql::<Uuid>(node.get(0)).context("Failed to read Uuid id")?;
           let parent_id = value_from_sql::<Option<Uuid>>(node.get(1)).context("Failed to read Uuid id")?;
 
-          let pad_metadata_encrypted = value_from_sql::<Vec<u8>>(node.get(2)).context("Failed to read Vec metadata")?;
+          let encrypted_pad = value_from_sql::<Vec<u8>>(node.get(2))
+            .context("Failed to read Vec metadata")
+            .and_then(|pad_metadata_encrypted| {
+              let sort_key = value_from_sql::<Option<String>>(node.get(3)).context("Failed to read sort key")?;
+              let revision = value_from_sql::<u64>(node.get(4)).context("Failed to read revision")?;
+              EncryptedPad::new(id, parent_id, pad_metadata_encrypted, sort_key, revision, master_key)
+            });
+          match encrypted_pad {
+            Ok(encrypted_pad) => {
+              self.pad_map.pads.insert(id, encrypted_pad);
+            },
+            Err(err) => self.quarantined_pads.push(QuarantinedPad { id, parent_id, error: err.to_string() })
+          }
+        }
+        // Nothing decrypting means the password is wrong rather than some pads being corrupt
+        if self.pad_map.pads.is_empty() && !self.quarantined_pads.is_empty() {
+          self.quarantined_pads.clear();
+          bail!("Failed to decrypt pads");
+        }
+        self.attach_orphans_to_recovered();
+        Ok(())
+      } else {
+        bail!("No password supplied");
+      }
+    } else {
+      bail!("No connection")
+    }
+  }
 
-          let encrypted_pad = EncryptedPad::new(id, parent_id, pad_metadata_encrypted, master_key)?;
-          self.pad_map.pads.insert(id, encrypted_pad);
+  // Children of quarantined pads are shown under an in-memory pad instead of disappearing from the tree
+  fn attach_orphans_to_recovered(&mut self) {
+    let orphan_ids: Vec<Uuid> = self.pad_map.pads.values()
+      .filter(|encrypted_pad| matches!(encrypted_pad.parent_id, Some(parent_id) if !self.pad_map.pads.contains_key(&parent_id)))
+      .map(|encrypted_pad| encrypted_pad.id)
+      .collect();
+    if orphan_ids.is_empty() {
+      return;
+    }
+    for id in orphan_ids {
+      if let Some(encrypted_pad) = self.pad_map.pads.get_mut(&id) {
+        encrypted_pad.parent_id = Some(RECOVERED_PAD_ID);
+      }
+    }
+    let metadata = serde_json::json!({
+      "type": "text",
+      "name": RECOVERED_PAD_NAME,
+      "createdAt": 0,
+      "lastModifiedAt": 0
+    });
+    self.pad_map.pads.insert(RECOVERED_PAD_ID, EncryptedPad {
+      id: RECOVERED_PAD_ID,
+      parent_id: None,
+      metadata: metadata.to_string(),
+      sort_key: None,
+      revision: 0
+    });
+  }
 
-          if let Some(parent_id) = parent_id {
-            node_db_map.insert(id, parent_id);
-          } else {
-            root_nodes.insert(id);
-          }
+  pub async fn get_node_tree(&mut self) -> Result<NodeTree, anyhow::Error> {
+    if self.node_tree.is_none() {
+      self.load_pad_map().await?;
+      self.purge_expired_trash().await?;
+      self.node_tree = Some(NodeTree::build(&self.pad_map));
+    }
+    match &self.node_tree {
+      Some(node_tree) => Ok(node_tree.clone()),
+      None => bail!("Node tree not loaded")
+    }
+  }
+
+  // For jobs that write pads outside the lock, the next get_node_tree reloads from the database
+  pub fn invalidate_node_tree(&mut self) {
+    self.node_tree = None;
+  }
+
+  pub async fn create_pad(&mut self, pad: Pad) -> Result<Uuid, anyhow::Error> {
+    let (pool, master_key) = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(master_key)) => (pool.clone(), *master_key),
+      _ => bail!("No connection and/or authentication")
+    };
+    let id = Uuid::new_v4();
+    let parent_id = pad.parent_id;
+    let metadata = PadNode::new(id, pad).create_node(&pool, &master_key).await?;
+
+    self.pad_map.pads.insert(id, EncryptedPad { id, parent_id, metadata, sort_key: None, revision: 0 });
+    if let Some(node_tree) = &mut self.node_tree {
+      node_tree.insert(Node::new(id), parent_id, &self.pad_map);
+    }
+    Ok(id)
+  }
+
+  pub async fn update_pad(&mut self, pad_node: PadNode) -> Result<u64, anyhow::Error> {
+    let (pool, master_key) = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(master_key)) => (pool.clone(), *master_key),
+      _ => bail!("No connection and/or authentication")
+    };
+    let id = pad_node.id;
+    let (revision, metadata) = pad_node.encrypt_and_save(&pool, &master_key).await?;
+
+    if let Some(encrypted_pad) = self.pad_map.pads.get_mut(&id) {
+      encrypted_pad.metadata = metadata;
+      encrypted_pad.revision = revision;
+      let parent_id = encrypted_pad.parent_id;
+      if let Some(node_tree) = &mut self.node_tree {
+        node_tree.resort_children(parent_id, &self.pad_map);
+      }
+    }
+    Ok(revision)
+  }
+
+  pub async fn delete_pad(&mut self, id: Uuid) -> Result<(), anyhow::Error> {
+    let pool = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => pool.clone(),
+      _ => bail!("No connection and/or authentication")
+    };
+    if id == RECOVERED_PAD_ID {
+      bail!("The {} pad is not stored in the vault", RECOVERED_PAD_NAME);
+    }
+    let deleted_at = now_millis();
+    // Quarantined pads are not in the pad map, the reloaded tree drops them and their orphaned children
+    if self.quarantined_pads.iter().any(|quarantined_pad| quarantined_pad.id == id) {
+      trash_node(&pool, id, deleted_at).await?;
+      self.invalidate_node_tree();
+      return Ok(());
+    }
+    if !self.pad_map.pads.contains_key(&id) {
+      bail!("No pad with id {}", id)
+    }
+    trash_node(&pool, id, deleted_at).await?;
 
-          let node = Rc::new(RefCell::new(Node::new(id)));
-          node_map.insert(id, node);
+    let removed_node = self.node_tree.as_mut().and_then(|node_tree| node_tree.remove(id));
+    match removed_node {
+      Some(node) => {
+        for removed_id in node.subtree_ids() {
+          self.pad_map.pads.remove(&removed_id);
         }
+      },
+      None => self.invalidate_node_tree()
+    }
+    Ok(())
+  }
 
-        populate_node_map(&mut node_map, &node_db_map, &root_nodes);
+  pub async fn list_trash(&self) -> Result<Vec<TrashedPad>, anyhow::Error> {
+    let (pool, master_key) = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(master_key)) => (pool, master_key),
+      _ => bail!("No connection and/or authentication")
+    };
+    let nodes = pool.select_query(
+      "SELECT id, parent_id, pad_metadata, deleted_at FROM node WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+      vec![],
+      4
+    ).await?;
+    // Rows that fail to read or decrypt are left out like quarantined pads, emptying the trash still removes them
+    let trashed_pads = nodes.iter()
+      .filter_map(|node| -> Option<TrashedPad> {
+        let pad_metadata_encrypted = value_from_sql::<Vec<u8>>(node.get(2)).ok()?;
+        Some(TrashedPad {
+          id: value_from_sql::<Uuid>(node.get(0)).ok()?,
+          parent_id: value_from_sql::<Option<Uuid>>(node.get(1)).ok()?,
+          metadata: crypto::decrypt_as_string(&pad_metadata_encrypted, master_key).ok()?,
+          deleted_at: value_from_sql::<u64>(node.get(3)).ok()?
+        })
+      })
+      .collect();
+    Ok(trashed_pads)
+  }
+
+  // Pads whose parent is gone or still in the trash are restored to the top level
+  pub async fn restore_pad(&mut self, id: Uuid) -> Result<(), anyhow::Error> {
+    let pool = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => pool.clone(),
+      _ => bail!("No connection and/or authentication")
+    };
+    let select_result = pool.select_query_single(
+      "SELECT parent_id FROM node WHERE id = ?1 AND deleted_at IS NOT NULL",
+      SqlParamsBuilder::new().add_param(id).build(),
+      1
+    ).await.context("No trashed pad with that id")?;
+    let parent_id = value_from_sql::<Option<Uuid>>(select_result.get(0))?
+      .filter(|parent_id| self.pad_map.pads.contains_key(parent_id));
+    pool.execute_query("UPDATE node SET deleted_at = NULL, parent_id = ?1 WHERE id = ?2",
+      SqlParamsBuilder::new().add_param(parent_id).add_param(id).build()
+    ).await?;
+    self.invalidate_node_tree();
+    Ok(())
+  }
 
-        node_tree.nodes = root_nodes
-          .into_iter()
-          .filter_map(|node_id| node_map.get(&node_id))
-          .map(|node| node.borrow().clone())
-          .collect();
+  pub async fn empty_trash(&mut self) -> Result<(), anyhow::Error> {
+    match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => {
+        pool.transaction(|transaction| delete_trashed_nodes(transaction, i64::MAX)).await?;
+        Ok(())
+      },
+      _ => bail!("No connection and/or authentication")
+    }
+  }
 
-        Ok(node_tree)
-      } else {
-        bail!("No password supplied");
+  // Only runs once the vault is unlocked, so opening a vault without the password never deletes anything
+  async fn purge_expired_trash(&self) -> Result<(), anyhow::Error> {
+    if let (Some(pool), Some(_)) = (&self.pool, &self.master_key) {
+      if let Some(retention_days) = VaultSettings::load(pool).await?.trash_retention_days {
+        let retention = Duration::from_secs(retention_days as u64 * 24 * 60 * 60);
+        let cutoff = SystemTime::now().checked_sub(retention).and_then(millis_since_epoch).unwrap_or_default() as i64;
+        pool.transaction(move |transaction| delete_trashed_nodes(transaction, cutoff)).await?;
+      }
+    }
+    Ok(())
+  }
+
+  // Rebuilds the database file so no freed pages are left behind in it
+  pub async fn compact_vault(&self) -> Result<(), anyhow::Error> {
+    match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => pool.vacuum().await,
+      _ => bail!("No connection and/or authentication")
+    }
+  }
+
+  pub async fn move_pads(&mut self, ids: Vec<Uuid>, new_parent_id: Option<Uuid>) -> Result<(), anyhow::Error> {
+    let pool = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => pool.clone(),
+      _ => bail!("No connection and/or authentication")
+    };
+    for id in &ids {
+      if !self.pad_map.pads.contains_key(id) {
+        bail!("No pad with id {}", id);
+      }
+    }
+    if new_parent_id == Some(RECOVERED_PAD_ID) || ids.contains(&RECOVERED_PAD_ID) {
+      bail!("The {} pad is not stored in the vault", RECOVERED_PAD_NAME);
+    }
+    let moved_ids: HashSet<Uuid> = ids.iter().copied().collect();
+    let mut ancestor_id = new_parent_id;
+    let mut visited = HashSet::new();
+    while let Some(id) = ancestor_id {
+      if moved_ids.contains(&id) {
+        bail!("A pad cannot be moved into itself or one of its descendants");
+      }
+      if !visited.insert(id) {
+        break;
+      }
+      ancestor_id = match self.pad_map.pads.get(&id) {
+        Some(encrypted_pad) => encrypted_pad.parent_id,
+        None => bail!("No pad with id {}", id)
+      };
+    }
+
+    let moved = ids.clone();
+    pool.transaction(move |transaction| {
+      for id in moved {
+        transaction.execute("UPDATE node SET parent_id = ?1, sort_key = NULL WHERE id = ?2", params![new_parent_id, id])?;
+      }
+      Ok(())
+    }).await?;
+
+    for id in ids {
+      if let Some(encrypted_pad) = self.pad_map.pads.get_mut(&id) {
+        encrypted_pad.parent_id = new_parent_id;
+        encrypted_pad.sort_key = None;
       }
+      let moved_node = self.node_tree.as_mut().and_then(|node_tree| node_tree.remove(id));
+      match moved_node {
+        Some(node) => {
+          if let Some(node_tree) = &mut self.node_tree {
+            node_tree.insert(node, new_parent_id, &self.pad_map);
+          }
+        },
+        None => self.invalidate_node_tree()
+      }
+    }
+    Ok(())
+  }
+
+  // Moves a pad to position among its siblings, counted without the pad itself
+  pub async fn reorder_pad(&mut self, id: Uuid, position: usize) -> Result<(), anyhow::Error> {
+    let pool = match (&self.pool, &self.master_key) {
+      (Some(pool), Some(_)) => pool.clone(),
+      _ => bail!("No connection and/or authentication")
+    };
+    let parent_id = match self.pad_map.pads.get(&id) {
+      Some(encrypted_pad) => encrypted_pad.parent_id,
+      None => bail!("No pad with id {}", id)
+    };
+    let mut siblings: Vec<&EncryptedPad> = self.pad_map.pads.values()
+      .filter(|encrypted_pad| encrypted_pad.parent_id == parent_id && encrypted_pad.id != id)
+      .collect();
+    siblings.sort_by_cached_key(|encrypted_pad| sibling_sort_key(encrypted_pad));
+    let position = position.min(siblings.len());
+
+    let keys: Vec<Option<&str>> = siblings.iter().map(|encrypted_pad| encrypted_pad.sort_key.as_deref()).collect();
+    let keys_usable = keys.iter().all(Option::is_some) && keys.windows(2).all(|pair| pair[0] < pair[1]);
+    let updates = if keys_usable {
+      let lower = if position > 0 { keys[position - 1] } else { None };
+      let upper = keys.get(position).copied().flatten();
+      vec![(id, key_between(lower, upper))]
     } else {
-      bail!("No connection")
+      // Siblings without usable keys are all given fresh ones in their current order
+      let mut ordered_ids: Vec<Uuid> = siblings.iter().map(|encrypted_pad| encrypted_pad.id).collect();
+      ordered_ids.insert(position, id);
+      let mut previous_key: Option<String> = None;
+      ordered_ids.into_iter()
+        .map(|sibling_id| {
+          let key = key_between(previous_key.as_deref(), None);
+          previous_key = Some(key.clone());
+          (sibling_id, key)
+        })
+        .collect()
+    };
+
+    let saved_updates = updates.clone();
+    pool.transaction(move |transaction| {
+      for (sibling_id, sort_key) in saved_updates {
+        transaction.execute("UPDATE node SET sort_key = ?1 WHERE id = ?2", params![sort_key, sibling_id])?;
+      }
+      Ok(())
+    }).await?;
+
+    for (sibling_id, sort_key) in updates {
+      if let Some(encrypted_pad) = self.pad_map.pads.get_mut(&sibling_id) {
+        encrypted_pad.sort_key = Some(sort_key);
+      }
     }
+    if let Some(node_tree) = &mut self.node_tree {
+      node_tree.resort_children(parent_id, &self.pad_map);
+    }
+    Ok(())
   }
 
   pub fn is_connected(&self) -> bool {
@@ -0,0 +1,118 @@
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{EncryptedPad, Node, PadMap};
+
+// Sort keys are fractional indexes over these digits, which sort the same as their ASCII bytes
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+type SiblingSortKey = (bool, Option<String>, String, u64, Uuid);
+
+fn digit_value(digit: u8) -> usize {
+  DIGITS.iter().position(|&value| value == digit).unwrap_or(0)
+}
+
+// Returns a key that sorts strictly between lower and upper, where None stands for the start or end of the list
+pub fn key_between(lower: Option<&str>, upper: Option<&str>) -> String {
+  midpoint(lower.unwrap_or("").as_bytes(), upper.map(str::as_bytes))
+}
+
+fn midpoint(lower: &[u8], upper: Option<&[u8]>) -> String {
+  if let Some(upper) = upper {
+    let prefix_len = upper.iter()
+      .enumerate()
+      .take_while(|&(index, &digit)| lower.get(index).copied().unwrap_or(DIGITS[0]) == digit)
+      .count();
+    if prefix_len > 0 {
+      let prefix = String::from_utf8_lossy(&upper[..prefix_len]).to_string();
+      return prefix + &midpoint(lower.get(prefix_len..).unwrap_or(&[]), Some(&upper[prefix_len..]));
+    }
+  }
+
+  let lower_digit = lower.first().map(|&digit| digit_value(digit)).unwrap_or(0);
+  let upper_digit = upper.and_then(|upper| upper.first()).map(|&digit| digit_value(digit)).unwrap_or(DIGITS.len());
+  if upper_digit - lower_digit > 1 {
+    (DIGITS[(lower_digit + upper_digit) / 2] as char).to_string()
+  } else if let Some(upper) = upper.filter(|upper| upper.len() > 1) {
+    (upper[0] as char).to_string()
+  } else {
+    (DIGITS[lower_digit] as char).to_string() + &midpoint(lower.get(1..).unwrap_or(&[]), None)
+  }
+}
+
+// Pads with a sort key come first in key order, the rest fall back to name and then creation date
+pub fn sibling_sort_key(encrypted_pad: &EncryptedPad) -> SiblingSortKey {
+  let metadata: Value = serde_json::from_str(&encrypted_pad.metadata).unwrap_or(Value::Null);
+  let name = metadata.get("name").and_then(Value::as_str).unwrap_or_default().to_lowercase();
+  let created_at = encrypted_pad.created_at().unwrap_or_default();
+  (encrypted_pad.sort_key.is_none(), encrypted_pad.sort_key.clone(), name, created_at, encrypted_pad.id)
+}
+
+pub fn sort_nodes(nodes: &mut [Node], pad_map: &PadMap) {
+  nodes.sort_by_cached_key(|node| pad_map.pads.get(&node.id).map(sibling_sort_key));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_between(lower: Option<&str>, upper: Option<&str>) -> String {
+    let key = key_between(lower, upper);
+    if let Some(lower) = lower {
+      assert!(lower < key.as_str(), "{} is not after {}", key, lower);
+    }
+    if let Some(upper) = upper {
+      assert!(key.as_str() < upper, "{} is not before {}", key, upper);
+    }
+    assert!(key.bytes().all(|digit| DIGITS.contains(&digit)), "{} has a digit outside DIGITS", key);
+    key
+  }
+
+  #[test]
+  fn first_key_is_between_the_ends() {
+    assert_between(None, None);
+  }
+
+  #[test]
+  fn appended_keys_keep_increasing() {
+    let mut last = assert_between(None, None);
+    for _ in 0..200 {
+      last = assert_between(Some(&last), None);
+    }
+  }
+
+  #[test]
+  fn prepended_keys_keep_decreasing() {
+    let mut first = assert_between(None, None);
+    for _ in 0..200 {
+      first = assert_between(None, Some(&first));
+    }
+  }
+
+  #[test]
+  fn repeated_inserts_between_neighbours() {
+    let lower = assert_between(None, None);
+    let mut upper = assert_between(Some(&lower), None);
+    for _ in 0..200 {
+      upper = assert_between(Some(&lower), Some(&upper));
+    }
+    let mut lower = lower;
+    for _ in 0..200 {
+      lower = assert_between(Some(&lower), Some(&upper));
+    }
+  }
+
+  #[test]
+  fn adjacent_digits_and_shared_prefixes() {
+    assert_between(Some("a"), Some("b"));
+    assert_between(Some("abc"), Some("abd"));
+    assert_between(Some("az"), Some("b"));
+    assert_between(Some("a"), Some("a1"));
+    assert_between(Some("0"), Some("1"));
+  }
+
+  #[test]
+  fn midpoint_of_empty_range_is_the_middle_digit() {
+    assert_eq!(midpoint(b"", None), (DIGITS[DIGITS.len() / 2] as char).to_string());
+  }
+}
\ No newline at end of file
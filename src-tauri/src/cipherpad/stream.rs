@@ -0,0 +1,470 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::bail;
+use rayon::prelude::*;
+use ring::digest;
+
+use super::crypto::{self, ChunkOpener, ChunkSealer, ContentHasher, COMPRESSION_LEVEL};
+
+const PARALLEL_BATCH_SIZE: usize = 16 * 1024 * 1024; // Plaintext bytes encrypted or decrypted together across threads
+const LEGACY_CHUNK_SIZE: usize = 4096; // Chunk size of pads written before the blob header existed
+pub const MIN_CHUNK_SIZE: usize = 4096;
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const BLOB_MAGIC: &[u8; 4] = b"CPAD";
+const BLOB_VERSION: u8 = 1;
+const BLOB_HEADER_SIZE: usize = 18; // Magic, version, flags, chunk size and chunk size table length
+const FLAG_COMPRESSED: u8 = 0b0000_0001; // Chunks are zstd compressed before being encrypted
+
+#[derive(Clone, Copy)]
+pub struct BlobOptions {
+  pub chunk_size: usize,
+  pub compress: bool
+}
+
+pub struct WrittenBlob {
+  pub header: Vec<u8>,
+  pub content_hash: Vec<u8>,
+  pub checksum: Vec<u8>,
+  pub plaintext_len: u64
+}
+
+// Unencrypted header at the start of a blob pad, followed by the encrypted chunk size table and the chunks
+struct BlobHeader {
+  flags: u8,
+  chunk_size: u32,
+  chunk_sizes_len: u64
+}
+
+impl BlobHeader {
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(BLOB_HEADER_SIZE);
+    bytes.extend(BLOB_MAGIC);
+    bytes.push(BLOB_VERSION);
+    bytes.push(self.flags);
+    bytes.extend(self.chunk_size.to_be_bytes());
+    bytes.extend(self.chunk_sizes_len.to_be_bytes());
+    bytes
+  }
+
+  fn read_from<R: Read>(reader: &mut R) -> Result<Self, anyhow::Error> {
+    let mut bytes = [0u8; BLOB_HEADER_SIZE];
+    reader.read_exact(&mut bytes)?;
+    if &bytes[0..4] != BLOB_MAGIC {
+      bail!("Blob is missing its header")
+    }
+    if bytes[4] != BLOB_VERSION {
+      bail!("Unsupported blob version {}", bytes[4])
+    }
+    Ok(Self {
+      flags: bytes[5],
+      chunk_size: u32::from_be_bytes(bytes[6..10].try_into()?),
+      chunk_sizes_len: u64::from_be_bytes(bytes[10..18].try_into()?)
+    })
+  }
+}
+
+// Capped by bytes rather than scaled with the thread count, so large chunk sizes cannot balloon the batch buffers
+fn batch_chunks(chunk_size: usize) -> usize {
+  (PARALLEL_BATCH_SIZE / chunk_size.max(1)).max(1)
+}
+
+struct PadChunk {
+  encrypted_offset: u64,
+  encrypted_size: usize,
+  plaintext_offset: u64,
+  plaintext_size: usize
+}
+
+// Reads the decrypted contents of a blob pad, decrypting only the chunks that are needed
+pub struct PadReader<R: Read + Seek> {
+  inner: R,
+  opener: ChunkOpener,
+  data_offset: u64,
+  chunks: Vec<PadChunk>,
+  batch_chunks: usize,
+  compressed: bool,
+  len: u64,
+  read_end: u64,
+  position: u64,
+  cached_chunks: Option<(usize, Vec<Vec<u8>>)>
+}
+
+impl<R: Read + Seek> PadReader<R> {
+  // Pads written before the blob header existed keep the chunk size table length in legacy_data_offset
+  pub fn new(mut inner: R, master_key: &[u8], legacy_data_offset: Option<usize>) -> Result<Self, anyhow::Error> {
+    inner.seek(SeekFrom::Start(0))?;
+    let (chunk_size, flags, chunk_sizes_len, data_offset) = match legacy_data_offset {
+      Some(data_offset) => (LEGACY_CHUNK_SIZE, 0, data_offset, data_offset as u64),
+      None => {
+        let header = BlobHeader::read_from(&mut inner)?;
+        let chunk_sizes_len = header.chunk_sizes_len as usize;
+        (header.chunk_size as usize, header.flags, chunk_sizes_len, (BLOB_HEADER_SIZE + chunk_sizes_len) as u64)
+      }
+    };
+    // Compressed pads store the plaintext size of each chunk after its encrypted size
+    let compressed = flags & FLAG_COMPRESSED != 0;
+    let entry_size = if compressed { 16 } else { 8 };
+    let mut encrypted_encrypted_chunk_sizes = vec![0u8; chunk_sizes_len];
+    inner.read_exact(&mut encrypted_encrypted_chunk_sizes)?;
+    let encrypted_chunk_sizes_bytes = crypto::decrypt(&encrypted_encrypted_chunk_sizes, master_key)?;
+
+    let mut chunks = Vec::new();
+    let mut encrypted_offset = 0u64;
+    let mut plaintext_offset = 0u64;
+    for chunk in encrypted_chunk_sizes_bytes.chunks_exact(entry_size) {
+      let encrypted_size = u64::from_be_bytes(chunk[0..8].try_into()?) as usize;
+      if encrypted_size < crypto::ENCRYPTION_OVERHEAD {
+        bail!("Invalid encrypted chunk size {}", encrypted_size)
+      }
+      let plaintext_size = if compressed {
+        u64::from_be_bytes(chunk[8..16].try_into()?) as usize
+      } else {
+        encrypted_size - crypto::ENCRYPTION_OVERHEAD
+      };
+      chunks.push(PadChunk {
+        encrypted_offset,
+        encrypted_size,
+        plaintext_offset,
+        plaintext_size
+      });
+      encrypted_offset += encrypted_size as u64;
+      plaintext_offset += plaintext_size as u64;
+    }
+
+    Ok(Self {
+      inner,
+      opener: ChunkOpener::new(master_key),
+      data_offset,
+      chunks,
+      batch_chunks: batch_chunks(chunk_size),
+      compressed,
+      len: plaintext_offset,
+      read_end: plaintext_offset,
+      position: 0,
+      cached_chunks: None
+    })
+  }
+
+  pub fn len(&self) -> u64 {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  // Stops decrypting ahead at end, so reading a short range only decrypts the chunks that cover it
+  pub fn set_read_end(&mut self, end: u64) {
+    self.read_end = end.min(self.len);
+  }
+
+  fn chunk_index(&self, position: u64) -> usize {
+    self.chunks.partition_point(|chunk| chunk.plaintext_offset + chunk.plaintext_size as u64 <= position)
+  }
+
+  // The chunk size table has to account for every byte stored after it
+  pub fn check_layout(&mut self) -> Result<(), anyhow::Error> {
+    let stored_len = self.inner.seek(SeekFrom::End(0))?;
+    let expected_len = self.data_offset + self.chunks.last()
+      .map(|chunk| chunk.encrypted_offset + chunk.encrypted_size as u64)
+      .unwrap_or(0);
+    if stored_len != expected_len {
+      bail!("Chunk size table covers {} bytes but the blob is {} bytes", expected_len, stored_len)
+    }
+    Ok(())
+  }
+
+  // Decrypts the chunk at index along with the chunks following it up to the read end, so sequential reads stay parallel
+  fn load_chunk(&mut self, index: usize) -> Result<&[u8], anyhow::Error> {
+    let is_loaded = matches!(&self.cached_chunks, Some((first_index, chunks)) if index >= *first_index && index < first_index + chunks.len());
+    if !is_loaded {
+      let last_index = self.chunk_index(self.read_end.saturating_sub(1)).clamp(index, self.chunks.len() - 1);
+      let batch = &self.chunks[index..(index + self.batch_chunks).min(last_index + 1)];
+      let batch_start = batch[0].encrypted_offset;
+      let batch_end = batch[batch.len() - 1].encrypted_offset + batch[batch.len() - 1].encrypted_size as u64;
+      let mut encrypted_batch = vec![0u8; (batch_end - batch_start) as usize];
+      self.inner.seek(SeekFrom::Start(self.data_offset + batch_start))?;
+      self.inner.read_exact(&mut encrypted_batch)?;
+      let opener = &self.opener;
+      let compressed = self.compressed;
+      let chunks = batch.par_iter()
+        .map(|chunk| -> Result<Vec<u8>, anyhow::Error> {
+          let chunk_start = (chunk.encrypted_offset - batch_start) as usize;
+          let decrypted_chunk = opener.decrypt(&encrypted_batch[chunk_start..chunk_start + chunk.encrypted_size])?;
+          if !compressed {
+            return Ok(decrypted_chunk);
+          }
+          let decompressed_chunk = zstd::bulk::decompress(&decrypted_chunk, chunk.plaintext_size)?;
+          if decompressed_chunk.len() != chunk.plaintext_size {
+            bail!("Decompressed chunk is {} bytes, expected {}", decompressed_chunk.len(), chunk.plaintext_size)
+          }
+          Ok(decompressed_chunk)
+        })
+        .collect::<Result<Vec<Vec<u8>>, anyhow::Error>>()?;
+      self.cached_chunks = Some((index, chunks));
+    }
+    match &self.cached_chunks {
+      Some((first_index, chunks)) => Ok(&chunks[index - first_index]),
+      None => bail!("Chunk {} was not loaded", index)
+    }
+  }
+}
+
+impl<R: Read + Seek> Read for PadReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() || self.position >= self.len {
+      return Ok(0);
+    }
+    let position = self.position;
+    let index = self.chunk_index(position);
+    let chunk_start = (position - self.chunks[index].plaintext_offset) as usize;
+    let chunk = self.load_chunk(index)
+      .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let bytes_read = buf.len().min(chunk.len() - chunk_start);
+    buf[..bytes_read].copy_from_slice(&chunk[chunk_start..chunk_start + bytes_read]);
+    self.position += bytes_read as u64;
+    Ok(bytes_read)
+  }
+}
+
+impl<R: Read + Seek> Seek for PadReader<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let position = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+    };
+    match position {
+      Some(position) => {
+        self.position = position;
+        Ok(position)
+      },
+      None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+    }
+  }
+}
+
+// Encrypts everything written to it into chunk_size chunks in the layout stored in a blob pad.
+// Each batch of chunks is encrypted in parallel while the previous batch is written out.
+pub struct PadWriter<W: Write + Send> {
+  inner: W,
+  master_key: Vec<u8>,
+  sealer: ChunkSealer,
+  content_hasher: ContentHasher,
+  checksum: digest::Context,
+  plaintext_len: u64,
+  options: BlobOptions,
+  batch_size: usize,
+  buffer: Vec<u8>,
+  encrypted_chunks: Vec<EncryptedChunk>,
+  encrypted_chunk_sizes: Vec<u8>
+}
+
+struct EncryptedChunk {
+  data: Vec<u8>,
+  plaintext_size: usize
+}
+
+fn encrypt_chunk(sealer: &ChunkSealer, chunk: &[u8], compress: bool) -> Result<EncryptedChunk, anyhow::Error> {
+  let data = if compress {
+    sealer.encrypt(&zstd::bulk::compress(chunk, COMPRESSION_LEVEL)?)?
+  } else {
+    sealer.encrypt(chunk)?
+  };
+  Ok(EncryptedChunk {
+    data,
+    plaintext_size: chunk.len()
+  })
+}
+
+fn write_encrypted_chunks<W: Write>(inner: &mut W, encrypted_chunk_sizes: &mut Vec<u8>, encrypted_chunks: Vec<EncryptedChunk>, compress: bool) -> Result<(), anyhow::Error> {
+  for encrypted_chunk in encrypted_chunks {
+    inner.write_all(&encrypted_chunk.data)?;
+    encrypted_chunk_sizes.extend((encrypted_chunk.data.len() as u64).to_be_bytes());
+    if compress {
+      encrypted_chunk_sizes.extend((encrypted_chunk.plaintext_size as u64).to_be_bytes());
+    }
+  }
+  Ok(())
+}
+
+impl<W: Write + Send> PadWriter<W> {
+  pub fn new(inner: W, master_key: &[u8], options: BlobOptions) -> Result<Self, anyhow::Error> {
+    if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&options.chunk_size) {
+      bail!("Chunk size must be between {} and {} bytes", MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+    }
+    let batch_size = options.chunk_size * batch_chunks(options.chunk_size);
+    Ok(Self {
+      inner,
+      master_key: master_key.to_vec(),
+      sealer: ChunkSealer::new(master_key)?,
+      content_hasher: ContentHasher::new(master_key)?,
+      checksum: digest::Context::new(&digest::SHA256),
+      plaintext_len: 0,
+      options,
+      batch_size,
+      buffer: Vec::with_capacity(batch_size),
+      encrypted_chunks: Vec::new(),
+      encrypted_chunk_sizes: Vec::new()
+    })
+  }
+
+  fn write_batch(&mut self) -> Result<(), anyhow::Error> {
+    let sealer = &self.sealer;
+    let BlobOptions { chunk_size, compress } = self.options;
+    let buffer = &self.buffer;
+    let inner = &mut self.inner;
+    let encrypted_chunk_sizes = &mut self.encrypted_chunk_sizes;
+    let previous_chunks = std::mem::take(&mut self.encrypted_chunks);
+    let (encrypted_chunks, write_result) = rayon::join(
+      || buffer.par_chunks(chunk_size)
+        .map(|chunk| encrypt_chunk(sealer, chunk, compress))
+        .collect::<Result<Vec<EncryptedChunk>, anyhow::Error>>(),
+      || write_encrypted_chunks(inner, encrypted_chunk_sizes, previous_chunks, compress)
+    );
+    write_result?;
+    self.encrypted_chunks = encrypted_chunks?;
+    self.buffer.clear();
+    Ok(())
+  }
+
+  // Encrypts the remaining data and returns the inner writer with the blob header and encrypted chunk size table,
+  // which have to be stored in front of the chunks, the keyed hash and the SHA-256 of everything written
+  pub fn finish(mut self) -> Result<(W, WrittenBlob), anyhow::Error> {
+    if !self.buffer.is_empty() {
+      self.write_batch()?;
+    }
+    let encrypted_chunks = std::mem::take(&mut self.encrypted_chunks);
+    write_encrypted_chunks(&mut self.inner, &mut self.encrypted_chunk_sizes, encrypted_chunks, self.options.compress)?;
+    self.inner.flush()?;
+    let encrypted_encrypted_chunk_sizes = crypto::encrypt(&self.encrypted_chunk_sizes, &self.master_key)?;
+    let header = BlobHeader {
+      flags: if self.options.compress { FLAG_COMPRESSED } else { 0 },
+      chunk_size: self.options.chunk_size as u32,
+      chunk_sizes_len: encrypted_encrypted_chunk_sizes.len() as u64
+    };
+    let mut blob_header = header.to_bytes();
+    blob_header.extend(encrypted_encrypted_chunk_sizes);
+    Ok((self.inner, WrittenBlob {
+      header: blob_header,
+      content_hash: self.content_hasher.finish(),
+      checksum: self.checksum.finish().as_ref().to_vec(),
+      plaintext_len: self.plaintext_len
+    }))
+  }
+}
+
+impl<W: Write + Send> Write for PadWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let bytes_written = buf.len().min(self.batch_size - self.buffer.len());
+    self.buffer.extend_from_slice(&buf[..bytes_written]);
+    self.content_hasher.update(&buf[..bytes_written]);
+    self.checksum.update(&buf[..bytes_written]);
+    self.plaintext_len += bytes_written as u64;
+    if self.buffer.len() == self.batch_size {
+      self.write_batch()
+        .map_err(io::Error::other)?;
+    }
+    Ok(bytes_written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  const MASTER_KEY: [u8; 32] = [7; 32];
+
+  // Repetitive enough to compress, with a counter so no two chunks are the same
+  fn sample_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| if i % 7 == 0 { (i / 7 % 251) as u8 } else { b"cipherpad"[i % 9] }).collect()
+  }
+
+  fn write_blob(data: &[u8], options: BlobOptions) -> Vec<u8> {
+    let mut pad_writer = PadWriter::new(Vec::new(), &MASTER_KEY, options).unwrap();
+    pad_writer.write_all(data).unwrap();
+    let (chunks, written_blob) = pad_writer.finish().unwrap();
+    assert_eq!(written_blob.plaintext_len, data.len() as u64);
+    let mut blob = written_blob.header;
+    blob.extend(chunks);
+    blob
+  }
+
+  fn read_at<R: Read + Seek>(pad_reader: &mut PadReader<R>, position: u64, len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    pad_reader.seek(SeekFrom::Start(position)).unwrap();
+    pad_reader.take(len as u64).read_to_end(&mut buf).unwrap();
+    buf
+  }
+
+  fn assert_round_trip(compress: bool) {
+    let data = sample_data(MIN_CHUNK_SIZE * 5 + 100);
+    let blob = write_blob(&data, BlobOptions { chunk_size: MIN_CHUNK_SIZE, compress });
+    let mut pad_reader = PadReader::new(Cursor::new(blob), &MASTER_KEY, None).unwrap();
+    assert_eq!(pad_reader.len(), data.len() as u64);
+    assert_eq!(pad_reader.compressed, compress);
+    pad_reader.check_layout().unwrap();
+    let mut decrypted = Vec::new();
+    pad_reader.seek(SeekFrom::Start(0)).unwrap();
+    pad_reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(decrypted, data);
+  }
+
+  #[test]
+  fn round_trips_without_compression() {
+    assert_round_trip(false);
+  }
+
+  #[test]
+  fn round_trips_with_compression() {
+    assert_round_trip(true);
+  }
+
+  #[test]
+  fn seeks_across_chunk_boundaries() {
+    let data = sample_data(MIN_CHUNK_SIZE * 4 + 10);
+    for compress in [false, true] {
+      let blob = write_blob(&data, BlobOptions { chunk_size: MIN_CHUNK_SIZE, compress });
+      let mut pad_reader = PadReader::new(Cursor::new(blob), &MASTER_KEY, None).unwrap();
+      for position in [MIN_CHUNK_SIZE * 3 - 5, 0, MIN_CHUNK_SIZE - 1, MIN_CHUNK_SIZE * 2, data.len() - 3] {
+        let expected = &data[position..(position + 20).min(data.len())];
+        assert_eq!(read_at(&mut pad_reader, position as u64, 20), expected);
+      }
+      // Reading past the read end still returns the data, it only stops decrypting ahead
+      pad_reader.set_read_end(MIN_CHUNK_SIZE as u64 + 1);
+      assert_eq!(read_at(&mut pad_reader, MIN_CHUNK_SIZE as u64 - 8, MIN_CHUNK_SIZE), &data[MIN_CHUNK_SIZE - 8..MIN_CHUNK_SIZE * 2 - 8]);
+      assert_eq!(pad_reader.seek(SeekFrom::End(-4)).unwrap(), data.len() as u64 - 4);
+      let mut tail = Vec::new();
+      pad_reader.read_to_end(&mut tail).unwrap();
+      assert_eq!(tail, &data[data.len() - 4..]);
+    }
+  }
+
+  #[test]
+  fn reads_legacy_layout() {
+    let data = sample_data(LEGACY_CHUNK_SIZE * 3 + 10);
+    let mut chunk_sizes = Vec::new();
+    let mut chunks = Vec::new();
+    for chunk in data.chunks(LEGACY_CHUNK_SIZE) {
+      let encrypted_chunk = crypto::encrypt(chunk, &MASTER_KEY).unwrap();
+      chunk_sizes.extend((encrypted_chunk.len() as u64).to_be_bytes());
+      chunks.extend(encrypted_chunk);
+    }
+    let mut blob = crypto::encrypt(&chunk_sizes, &MASTER_KEY).unwrap();
+    let legacy_data_offset = blob.len();
+    blob.extend(chunks);
+
+    let mut pad_reader = PadReader::new(Cursor::new(blob), &MASTER_KEY, Some(legacy_data_offset)).unwrap();
+    assert_eq!(pad_reader.len(), data.len() as u64);
+    pad_reader.check_layout().unwrap();
+    assert_eq!(read_at(&mut pad_reader, LEGACY_CHUNK_SIZE as u64 - 2, 4), &data[LEGACY_CHUNK_SIZE - 2..LEGACY_CHUNK_SIZE + 2]);
+    assert_eq!(read_at(&mut pad_reader, 0, data.len()), data);
+  }
+}
@@ -0,0 +1,17 @@
+use std::io::Cursor;
+
+use image::{io::Reader as ImageReader, ImageOutputFormat};
+
+const THUMBNAIL_SIZE: u32 = 256; // Longest side of a thumbnail in pixels
+const THUMBNAIL_QUALITY: u8 = 80; // JPEG quality thumbnails are encoded with
+
+// Decodes the image in memory and returns a JPEG that fits in THUMBNAIL_SIZE, so no plaintext is written to disk
+pub fn generate_thumbnail(file: &str) -> Result<Vec<u8>, anyhow::Error> {
+  let image = ImageReader::open(file)?
+    .with_guessed_format()?
+    .decode()?;
+  let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).into_rgb8();
+  let mut jpeg = Vec::new();
+  thumbnail.write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY))?;
+  Ok(jpeg)
+}
\ No newline at end of file
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use super::{Node, NodeTree, PadMap, ordering::sort_nodes};
+
+fn build_node(id: Uuid, children_by_parent: &mut HashMap<Option<Uuid>, Vec<Uuid>>, pad_map: &PadMap) -> Node {
+  let mut node = Node::new(id);
+  node.children = children_by_parent.remove(&Some(id))
+    .unwrap_or_default()
+    .into_iter()
+    .map(|child_id| build_node(child_id, children_by_parent, pad_map))
+    .collect();
+  sort_nodes(&mut node.children, pad_map);
+  node
+}
+
+fn find_node_mut(nodes: &mut [Node], id: Uuid) -> Option<&mut Node> {
+  for node in nodes.iter_mut() {
+    if node.id == id {
+      return Some(node);
+    }
+    if let Some(found) = find_node_mut(&mut node.children, id) {
+      return Some(found);
+    }
+  }
+  None
+}
+
+fn remove_node(nodes: &mut Vec<Node>, id: Uuid) -> Option<Node> {
+  if let Some(index) = nodes.iter().position(|node| node.id == id) {
+    return Some(nodes.remove(index));
+  }
+  nodes.iter_mut().find_map(|node| remove_node(&mut node.children, id))
+}
+
+impl NodeTree {
+  // Groups children by parent once, so every pad is visited a single time
+  pub fn build(pad_map: &PadMap) -> Self {
+    let mut children_by_parent: HashMap<Option<Uuid>, Vec<Uuid>> = HashMap::new();
+    for encrypted_pad in pad_map.pads.values() {
+      children_by_parent.entry(encrypted_pad.parent_id).or_default().push(encrypted_pad.id);
+    }
+    let mut node_tree = NodeTree::new();
+    node_tree.nodes = children_by_parent.remove(&None)
+      .unwrap_or_default()
+      .into_iter()
+      .map(|id| build_node(id, &mut children_by_parent, pad_map))
+      .collect();
+    sort_nodes(&mut node_tree.nodes, pad_map);
+    node_tree
+  }
+
+  fn children_mut(&mut self, parent_id: Option<Uuid>) -> Option<&mut Vec<Node>> {
+    match parent_id {
+      Some(parent_id) => find_node_mut(&mut self.nodes, parent_id).map(|node| &mut node.children),
+      None => Some(&mut self.nodes)
+    }
+  }
+
+  pub fn insert(&mut self, node: Node, parent_id: Option<Uuid>, pad_map: &PadMap) {
+    if let Some(children) = self.children_mut(parent_id) {
+      children.push(node);
+      sort_nodes(children, pad_map);
+    }
+  }
+
+  pub fn remove(&mut self, id: Uuid) -> Option<Node> {
+    remove_node(&mut self.nodes, id)
+  }
+
+  pub fn resort_children(&mut self, parent_id: Option<Uuid>, pad_map: &PadMap) {
+    if let Some(children) = self.children_mut(parent_id) {
+      sort_nodes(children, pad_map);
+    }
+  }
+}
+
+impl Node {
+  pub fn subtree_ids(&self) -> Vec<Uuid> {
+    let mut ids = vec![self.id];
+    for child in &self.children {
+      ids.extend(child.subtree_ids());
+    }
+    ids
+  }
+}
\ No newline at end of file
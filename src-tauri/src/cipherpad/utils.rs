@@ -1,9 +1,137 @@
-use std::{env, path::PathBuf, io::{BufReader, Read}};
+use std::{env, io::{Read, Write}, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
 
-use rusqlite::blob::Blob;
+use ring::digest;
+use serde::{Serialize, Deserialize};
 use tokio::fs::File;
 
-pub const CHUNK_SIZE: usize = 4096; // Size to chunk files when encrypting them
+pub const CHUNK_SIZE: usize = 64 * 1024; // Size to chunk files when encrypting them
+pub const LARGE_CHUNK_SIZE: usize = 1024 * 1024; // Size to chunk large files such as media when encrypting them
+const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024; // Files at least this large are chunked with LARGE_CHUNK_SIZE
+pub const MEDIA_SNIFF_SIZE: u64 = 8192; // Bytes read from the start of a file to detect its media type
+
+const UNCOMPRESSED_MEDIA_TYPES: [&str; 8] = [
+  "image/bmp",
+  "image/x-ms-bmp",
+  "image/tiff",
+  "image/svg+xml",
+  "image/x-icon",
+  "audio/wav",
+  "audio/x-wav",
+  "audio/aiff"
+];
+
+// Media types whose data is already compressed, so compressing them again only costs time
+pub fn is_compressed_media_type(media_type: &str) -> bool {
+  if UNCOMPRESSED_MEDIA_TYPES.contains(&media_type) {
+    return false;
+  }
+  media_type.starts_with("image/")
+    || media_type.starts_with("audio/")
+    || media_type.starts_with("video/")
+    || media_type.contains("zip")
+    || media_type.starts_with("application/vnd.openxmlformats-officedocument")
+    || media_type.starts_with("application/vnd.oasis.opendocument")
+    || matches!(media_type,
+      "application/pdf"
+      | "application/java-archive"
+      | "application/vnd.android.package-archive"
+      | "application/x-7z-compressed"
+      | "application/vnd.rar"
+      | "application/x-rar-compressed"
+      | "application/x-bzip2"
+      | "application/x-xz"
+      | "application/x-lzip"
+      | "application/x-lzma"
+      | "application/zstd"
+      | "application/x-compress"
+    )
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+  Image,
+  Audio,
+  Video,
+  Document,
+  Archive,
+  Other
+}
+
+pub fn media_kind(media_type: &str) -> MediaKind {
+  if media_type.starts_with("image/") {
+    MediaKind::Image
+  } else if media_type.starts_with("audio/") {
+    MediaKind::Audio
+  } else if media_type.starts_with("video/") {
+    MediaKind::Video
+  } else if media_type.starts_with("text/")
+    || media_type.starts_with("application/vnd.openxmlformats-officedocument")
+    || media_type.starts_with("application/vnd.oasis.opendocument")
+    || media_type.starts_with("application/vnd.ms-")
+    || matches!(media_type,
+      "application/pdf"
+      | "application/msword"
+      | "application/rtf"
+      | "application/epub+zip"
+      | "application/json"
+      | "application/xml"
+    ) {
+    MediaKind::Document
+  } else if media_type.contains("zip")
+    || media_type.contains("compress")
+    || matches!(media_type,
+      "application/x-tar"
+      | "application/vnd.rar"
+      | "application/x-rar-compressed"
+      | "application/x-bzip2"
+      | "application/x-xz"
+      | "application/x-lzip"
+      | "application/x-lzma"
+      | "application/zstd"
+      | "application/java-archive"
+      | "application/vnd.android.package-archive"
+    ) {
+    MediaKind::Archive
+  } else {
+    MediaKind::Other
+  }
+}
+
+const RESERVED_FILE_NAMES: [&str; 22] = [
+  "CON", "PRN", "AUX", "NUL",
+  "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+  "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+// Replaces characters that are invalid in file names on any platform we run on and avoids names Windows reserves
+pub fn sanitize_file_name(name: &str) -> String {
+  let name: String = name.chars()
+    .map(|character| match character {
+      '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+      character if character.is_control() => '_',
+      character => character
+    })
+    .collect();
+  let name = name.trim_start().trim_end_matches(|character: char| character == '.' || character.is_whitespace()).to_string();
+  let stem = name.split('.').next().unwrap_or_default().to_uppercase();
+  if name.is_empty() {
+    "Untitled".to_string()
+  } else if RESERVED_FILE_NAMES.contains(&stem.as_str()) {
+    format!("_{}", name)
+  } else {
+    name
+  }
+}
+
+pub fn chunk_size_for_file(file_size: u64) -> usize {
+  if file_size >= LARGE_FILE_SIZE {
+    LARGE_CHUNK_SIZE
+  } else {
+    CHUNK_SIZE
+  }
+}
+
 pub async fn create_temp_file() -> Result<(PathBuf, File), anyhow::Error> {
   let mut temp_path = env::temp_dir();
   temp_path.push(format!("cipherpad_{}", uuid::Uuid::new_v4()));
@@ -11,14 +139,88 @@ pub async fn create_temp_file() -> Result<(PathBuf, File), anyhow::Error> {
   Ok((temp_path, temp_file))
 }
 
-pub fn read_exact_chunk(reader: &mut BufReader<Blob<'_>>, buffer: &mut Vec<u8>) -> Result<usize, anyhow::Error>{
-  let mut total_bytes_read = 0;
-  while total_bytes_read < buffer.len() {
-    let bytes_read = reader.read(&mut buffer[total_bytes_read..])?;
+// None for times before the epoch
+pub fn millis_since_epoch(time: SystemTime) -> Option<u64> {
+  time.duration_since(UNIX_EPOCH).ok().map(|time| time.as_millis() as u64)
+}
+
+pub fn now_millis() -> u64 {
+  millis_since_epoch(SystemTime::now()).unwrap_or_default()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Copies reader into writer and returns the hex SHA-256 of everything copied
+pub fn copy_with_checksum<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<String, anyhow::Error> {
+  let mut checksum = digest::Context::new(&digest::SHA256);
+  let mut buffer = vec![0u8; CHUNK_SIZE];
+  loop {
+    let bytes_read = reader.read(&mut buffer)?;
     if bytes_read == 0 {
       break;
     }
-    total_bytes_read += bytes_read;
+    checksum.update(&buffer[..bytes_read]);
+    writer.write_all(&buffer[..bytes_read])?;
+  }
+  writer.flush()?;
+  Ok(to_hex(checksum.finish().as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn already_compressed_media_types() {
+    for media_type in ["image/jpeg", "image/png", "audio/mpeg", "video/mp4", "application/zip", "application/pdf", "application/x-7z-compressed"] {
+      assert!(is_compressed_media_type(media_type), "{}", media_type);
+    }
+  }
+
+  #[test]
+  fn uncompressed_media_types() {
+    for media_type in ["image/bmp", "image/svg+xml", "audio/wav", "text/plain", "application/json", "application/octet-stream"] {
+      assert!(!is_compressed_media_type(media_type), "{}", media_type);
+    }
+  }
+  #[test]
+  fn media_kinds() {
+    assert!(matches!(media_kind("image/webp"), MediaKind::Image));
+    assert!(matches!(media_kind("audio/flac"), MediaKind::Audio));
+    assert!(matches!(media_kind("video/webm"), MediaKind::Video));
+    assert!(matches!(media_kind("application/pdf"), MediaKind::Document));
+    assert!(matches!(media_kind("application/vnd.openxmlformats-officedocument.wordprocessingml.document"), MediaKind::Document));
+    assert!(matches!(media_kind("text/plain"), MediaKind::Document));
+    assert!(matches!(media_kind("application/zip"), MediaKind::Archive));
+    assert!(matches!(media_kind("application/x-tar"), MediaKind::Archive));
+    assert!(matches!(media_kind("application/octet-stream"), MediaKind::Other));
+  }
+
+  #[test]
+  fn epub_is_a_document_not_an_archive() {
+    assert!(matches!(media_kind("application/epub+zip"), MediaKind::Document));
+  }
+  #[test]
+  fn invalid_characters_are_replaced() {
+    assert_eq!(sanitize_file_name("a/b\\c:d*e?f\"g<h>i|j"), "a_b_c_d_e_f_g_h_i_j");
+    assert_eq!(sanitize_file_name("tab\there"), "tab_here");
+  }
+
+  #[test]
+  fn trailing_dots_and_spaces_are_trimmed() {
+    assert_eq!(sanitize_file_name("  notes.md. . "), "notes.md");
+    assert_eq!(sanitize_file_name("notes..."), "notes");
+    assert_eq!(sanitize_file_name(" ... "), "Untitled");
+    assert_eq!(sanitize_file_name(""), "Untitled");
+  }
+
+  #[test]
+  fn reserved_windows_names_are_prefixed() {
+    assert_eq!(sanitize_file_name("CON"), "_CON");
+    assert_eq!(sanitize_file_name("nul.txt"), "_nul.txt");
+    assert_eq!(sanitize_file_name("Com1.tar.gz"), "_Com1.tar.gz");
+    assert_eq!(sanitize_file_name("CONSOLE"), "CONSOLE");
   }
-  Ok(total_bytes_read)
- }
\ No newline at end of file
+}
\ No newline at end of file
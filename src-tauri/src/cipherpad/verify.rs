@@ -0,0 +1,183 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use anyhow::{bail, Context};
+use rusqlite::types::Value as SqlValue;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::{DatabasePool, EncryptedPad, Job, db::value_from_sql, jobs::JobReader, utils::copy_with_checksum};
+
+const INTEGRITY_CHECK_OK: &str = "ok";
+
+#[derive(Serialize)]
+pub struct VerifyFailure {
+  id: Uuid,
+  error: String
+}
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+  #[serde(rename = "checkedPads")]
+  checked_pads: usize,
+  failures: Vec<VerifyFailure>,
+  orphans: Vec<Uuid>,
+  #[serde(rename = "cyclePads")]
+  cycle_pads: Vec<Uuid>,
+  #[serde(rename = "integrityErrors")]
+  integrity_errors: Vec<String>
+}
+
+async fn verify_blob(pool: &DatabasePool, master_key: &[u8], encrypted_pad: EncryptedPad, job: Arc<Job>) -> Result<(), anyhow::Error> {
+  let blob_pad_metadata = encrypted_pad.clone().get_blob_pad_metadata()?;
+  let (len, checksum) = encrypted_pad.read_pad_data(pool, master_key, move |mut pad_reader| {
+    pad_reader.check_layout()?;
+    let len = pad_reader.len();
+    let mut job_reader = JobReader::new(pad_reader, job, len);
+    let checksum = copy_with_checksum(&mut job_reader, &mut std::io::sink())?;
+    Ok((len, checksum))
+  }).await?;
+  if let Some(original_size) = blob_pad_metadata.original_size {
+    if original_size != len {
+      bail!("Blob holds {} bytes but the pad metadata records {}", len, original_size)
+    }
+  }
+  if let Some(sha256) = blob_pad_metadata.sha256 {
+    if sha256 != checksum {
+      bail!("Checksum {} does not match the recorded {}", checksum, sha256)
+    }
+  }
+  Ok(())
+}
+
+// Decrypts the metadata and all of the data of a single pad
+async fn verify_pad(pool: &DatabasePool, master_key: &[u8], id: Uuid, parent_id: Option<Uuid>, pad_metadata_encrypted: Vec<u8>, job: Arc<Job>) -> Result<(), anyhow::Error> {
+  let encrypted_pad = EncryptedPad::new(id, parent_id, pad_metadata_encrypted, None, 0, master_key).context("Failed to decrypt metadata")?;
+  let metadata: Value = serde_json::from_str(&encrypted_pad.metadata).context("Failed to parse metadata")?;
+  job.set_current_item(metadata.get("name").and_then(Value::as_str).unwrap_or_default().to_string());
+  match metadata.get("type").and_then(Value::as_str) {
+    Some("blob") => verify_blob(pool, master_key, encrypted_pad, job).await,
+    _ => {
+      encrypted_pad.decrypt_pad_data(master_key, pool).await.context("Failed to decrypt data")?;
+      Ok(())
+    }
+  }
+}
+
+// Returns every pad on a parent_id cycle, which makes it unreachable from the top of the tree
+fn find_cycle_pads(parents: &HashMap<Uuid, Option<Uuid>>) -> HashSet<Uuid> {
+  let mut acyclic_pads = HashSet::new();
+  let mut cycle_pads = HashSet::new();
+  for &start_id in parents.keys() {
+    let mut path = Vec::new();
+    let mut on_path = HashSet::new();
+    let mut current_id = Some(start_id);
+    while let Some(id) = current_id {
+      if acyclic_pads.contains(&id) || cycle_pads.contains(&id) {
+        break;
+      }
+      if !on_path.insert(id) {
+        let cycle_start = path.iter().position(|&path_id| path_id == id).unwrap_or(0);
+        cycle_pads.extend(path[cycle_start..].iter().copied());
+        break;
+      }
+      path.push(id);
+      current_id = parents.get(&id).copied().flatten();
+    }
+    acyclic_pads.extend(path.into_iter().filter(|id| !cycle_pads.contains(id)));
+  }
+  cycle_pads
+}
+
+pub async fn verify_vault(pool: &DatabasePool, master_key: &[u8], job: Arc<Job>) -> Result<VerifyReport, anyhow::Error> {
+  let integrity_errors = pool.select_query("PRAGMA integrity_check", vec![], 1).await?
+    .into_iter()
+    .filter_map(|row| match row.get(0) {
+      Some(SqlValue::Text(message)) if message == INTEGRITY_CHECK_OK => None,
+      Some(SqlValue::Text(message)) => Some(message.clone()),
+      _ => None
+    })
+    .collect();
+
+  let nodes = pool.select_query("SELECT id, parent_id, pad_metadata FROM node", vec![], 3).await?;
+  let mut parents = HashMap::new();
+  let mut failures = Vec::new();
+  for node in &nodes {
+    let id = value_from_sql::<Uuid>(node.get(0)).context("Failed to read Uuid id")?;
+    let parent_id = value_from_sql::<Option<Uuid>>(node.get(1)).context("Failed to read Uuid id")?;
+    parents.insert(id, parent_id);
+
+    let verify_result = match value_from_sql::<Vec<u8>>(node.get(2)) {
+      Ok(pad_metadata_encrypted) => verify_pad(pool, master_key, id, parent_id, pad_metadata_encrypted, job.clone()).await,
+      Err(err) => Err(err.into())
+    };
+    if let Err(err) = verify_result {
+      job.check_cancelled()?;
+      failures.push(VerifyFailure {
+        id,
+        error: format!("{:#}", err)
+      });
+    }
+  }
+
+  let orphans = parents.iter()
+    .filter(|(_, parent_id)| matches!(parent_id, Some(parent_id) if !parents.contains_key(parent_id)))
+    .map(|(&id, _)| id)
+    .collect();
+  let cycle_pads = find_cycle_pads(&parents).into_iter().collect();
+
+  Ok(VerifyReport {
+    checked_pads: nodes.len(),
+    failures,
+    orphans,
+    cycle_pads,
+    integrity_errors
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ids(count: usize) -> Vec<Uuid> {
+    (0..count).map(|_| Uuid::new_v4()).collect()
+  }
+
+  #[test]
+  fn tree_has_no_cycles() {
+    let ids = ids(4);
+    let parents = HashMap::from([(ids[0], None), (ids[1], Some(ids[0])), (ids[2], Some(ids[1])), (ids[3], Some(ids[0]))]);
+    assert!(find_cycle_pads(&parents).is_empty());
+  }
+
+  #[test]
+  fn missing_parent_is_not_a_cycle() {
+    let ids = ids(3);
+    let parents = HashMap::from([(ids[0], Some(ids[2])), (ids[1], Some(ids[0]))]);
+    assert!(find_cycle_pads(&parents).is_empty());
+  }
+
+  #[test]
+  fn pad_that_is_its_own_parent() {
+    let ids = ids(2);
+    let parents = HashMap::from([(ids[0], Some(ids[0])), (ids[1], Some(ids[0]))]);
+    assert_eq!(find_cycle_pads(&parents), HashSet::from([ids[0]]));
+  }
+
+  #[test]
+  fn pads_leading_into_a_cycle_are_not_part_of_it() {
+    let ids = ids(6);
+    let parents = HashMap::from([
+      (ids[0], Some(ids[1])), (ids[1], Some(ids[2])), (ids[2], Some(ids[0])),
+      (ids[3], Some(ids[0])), (ids[4], Some(ids[3])), (ids[5], None)
+    ]);
+    assert_eq!(find_cycle_pads(&parents), HashSet::from([ids[0], ids[1], ids[2]]));
+  }
+
+  #[test]
+  fn separate_cycles_are_all_found() {
+    let ids = ids(4);
+    let parents = HashMap::from([(ids[0], Some(ids[1])), (ids[1], Some(ids[0])), (ids[2], Some(ids[3])), (ids[3], Some(ids[2]))]);
+    assert_eq!(find_cycle_pads(&parents), ids.into_iter().collect());
+  }
+}
\ No newline at end of file
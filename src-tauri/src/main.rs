@@ -2,12 +2,13 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod cipherpad;
+mod protocol;
 
 use std::sync::Arc;
 use base64::{Engine, engine::general_purpose};
-use cipherpad::{Cipherpad, PadNode, NodeTree, PadMap, Pad, EncryptedPad};
-use file_format::FileFormat;
-use tauri::async_runtime::Mutex;
+use serde::Serialize;
+use cipherpad::{Cipherpad, DecryptedPad, RevisionConflict, PadNode, NodeTree, PadMap, Pad, EncryptedPad, DatabasePool, Job, VaultSettings, ImportOptions, ImportReport, ExportReport, ArchiveReport, BackupReport, QuarantinedPad, TrashedPad, VerifyReport};
+use tauri::{async_runtime::Mutex, Manager};
 use uuid::Uuid;
 
 #[tauri::command]
@@ -38,14 +39,14 @@ async fn unlock_cipherpad(
   let mut cipherpad = state.inner().lock().await;
   if cipherpad.is_connected() {
     if let None = cipherpad.master_key {
-      let derive_key_result = cipherpad.derive_master_key(&password).await;
-      if derive_key_result.is_err() {
-        return Err(format!("Error deriving key"))
+      if let Err(err) = cipherpad.derive_master_key(&password).await {
+        return Err(format!("Error deriving key: {}", err))
       }
       match cipherpad.get_node_tree().await {
         Ok(tree) => Ok(tree),
         Err(err) => {
           cipherpad.master_key = None;
+          cipherpad.invalidate_node_tree();
           Err(format!("Error: {}", err))
         }
       }
@@ -62,51 +63,83 @@ async fn create_pad(
   pad: Pad,
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
 ) -> Result<String, String> {
-  let cipherpad = state.inner().lock().await;
-  let id = Uuid::new_v4();
-  let pad_node = PadNode::new(id, pad);
-  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
-    match pad_node.create_node(pool, master_key).await {
-      Ok(_) => {
-        Ok(id.to_string())
-      },
-      Err(err) => Err(format!("Error: {}", err))
-    }
-  } else {
-    Err(format!("No connection and/or authentication"))
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.create_pad(pad).await {
+    Ok(id) => {
+      Ok(id.to_string())
+    },
+    Err(err) => Err(format!("Error: {}", err))
   }
 }
 
+// Carries the current revision when the save failed because the pad changed since it was loaded
+#[derive(Serialize)]
+struct SaveError {
+  message: String,
+  #[serde(rename = "currentRevision", skip_serializing_if = "Option::is_none")]
+  current_revision: Option<u64>
+}
+
 #[tauri::command]
 async fn update_pad(
   pad_node: PadNode,
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
-) -> Result<(), String> {
-  let cipherpad = state.inner().lock().await;
-  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
-    match pad_node.encrypt_and_save(pool, master_key).await {
-      Ok(_) => Ok(()),
-      Err(err) => Err(format!("Error saving pad: {}", err))
-    }
+) -> Result<u64, SaveError> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.update_pad(pad_node).await {
+    Ok(revision) => Ok(revision),
+    Err(err) => Err(SaveError {
+      message: format!("Error saving pad: {}", err),
+      current_revision: err.downcast_ref::<RevisionConflict>().map(|conflict| conflict.current_revision)
+    })
+  }
+}
+
+async fn start_job(
+  job_id: Uuid,
+  window: tauri::Window,
+  state: &tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<(DatabasePool, Vec<u8>, Arc<Job>), String> {
+  let mut cipherpad = state.inner().lock().await;
+  let connection = match (&cipherpad.pool, &cipherpad.master_key) {
+    (Some(pool), Some(master_key)) => Some((pool.clone(), master_key.to_vec())),
+    _ => None
+  };
+  if let Some((pool, master_key)) = connection {
+    let job = Arc::new(Job::new(job_id, move |progress| {
+      let _ = window.emit("job-progress", progress);
+    }));
+    cipherpad.jobs.insert(job_id, job.clone());
+    Ok((pool, master_key, job))
   } else {
-    Err(format!("No connection"))
+    Err("No connection".to_string())
   }
 }
 
+async fn finish_job(
+  job_id: Uuid,
+  state: &tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) {
+  let mut cipherpad = state.inner().lock().await;
+  cipherpad.jobs.remove(&job_id);
+}
+
 #[tauri::command]
 async fn encrypt_file_to_pad(
   encrypted_pad: EncryptedPad,
   file: String,
+  options: ImportOptions,
+  job_id: Uuid,
+  window: tauri::Window,
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
-) -> Result<(), String> {
-  let cipherpad = state.inner().lock().await;
-  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
-    match encrypted_pad.encrypt_file_to_pad(pool, master_key, &file).await {
-      Ok(_) => Ok(()),
-      Err(err) => Err(format!("Error saving file to pad: {}", err))
-    }
-  } else {
-    Err(format!("No connection"))
+) -> Result<Vec<String>, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let result = encrypted_pad.encrypt_file_to_pad(&pool, &master_key, &file, options, job).await;
+  finish_job(job_id, &state).await;
+  state.inner().lock().await.invalidate_node_tree();
+  match result {
+    Ok(stripped_fields) => Ok(stripped_fields),
+    Err(err) => Err(format!("Error saving file to pad: {}", err))
   }
 }
 
@@ -114,16 +147,124 @@ async fn encrypt_file_to_pad(
 async fn decrypt_pad_to_file(
   encrypted_pad: EncryptedPad,
   file: String,
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<(), String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let result = encrypted_pad.decrypt_pad_to_file(&pool, &master_key, &file, job).await;
+  finish_job(job_id, &state).await;
+  match result {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error decrypting pad to file: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn import_directory(
+  directory: String,
+  parent_id: Option<Uuid>,
+  strip_metadata: Option<bool>,
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<ImportReport, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let result = cipherpad::import_directory(&pool, &master_key, &directory, parent_id, strip_metadata, job).await;
+  finish_job(job_id, &state).await;
+  state.inner().lock().await.invalidate_node_tree();
+  match result {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error importing directory: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn export_subtree(
+  id: Uuid,
+  directory: String,
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<ExportReport, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let pad_map = state.inner().lock().await.pad_map.clone();
+  let result = cipherpad::export_subtree(&pool, &master_key, pad_map, id, &directory, job).await;
+  finish_job(job_id, &state).await;
+  match result {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error exporting pads: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn export_archive(
+  id: Option<Uuid>,
+  file: String,
+  passphrase: String,
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<ArchiveReport, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let (pad_map, quarantined_pads) = {
+    let cipherpad = state.inner().lock().await;
+    (cipherpad.pad_map.clone(), cipherpad.quarantined_pads.clone())
+  };
+  let result = cipherpad::export_archive(&pool, &master_key, pad_map, &quarantined_pads, id, &file, &passphrase, job).await;
+  finish_job(job_id, &state).await;
+  match result {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error exporting archive: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn import_archive(
+  file: String,
+  passphrase: String,
+  parent_id: Option<Uuid>,
+  preserve_ids: Option<bool>,
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<ArchiveReport, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let result = cipherpad::import_archive(&pool, &master_key, &file, &passphrase, parent_id, preserve_ids.unwrap_or(false), job).await;
+  finish_job(job_id, &state).await;
+  state.inner().lock().await.invalidate_node_tree();
+  match result {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error importing archive: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn verify_vault(
+  job_id: Uuid,
+  window: tauri::Window,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<VerifyReport, String> {
+  let (pool, master_key, job) = start_job(job_id, window, &state).await?;
+  let result = cipherpad::verify_vault(&pool, &master_key, job).await;
+  finish_job(job_id, &state).await;
+  match result {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error verifying vault: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn cancel_job(
+  job_id: Uuid,
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
 ) -> Result<(), String> {
   let cipherpad = state.inner().lock().await;
-  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
-    match encrypted_pad.decrypt_pad_to_file(pool, master_key, &file).await {
-      Ok(_) => Ok(()),
-      Err(err) => Err(format!("Error decrypting pad to file: {}", err))
-    }
+  if let Some(job) = cipherpad.jobs.get(&job_id) {
+    job.cancel();
+    Ok(())
   } else {
-    Err(format!("No connection"))
+    Err("No job with that id".to_string())
   }
 }
 
@@ -135,7 +276,7 @@ async fn decrypt_pad_to_blob(
   let cipherpad = state.inner().lock().await;
   if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
     match encrypted_pad.decrypt_pad_to_blob(pool, master_key).await {
-      Ok(blob) => Ok((general_purpose::STANDARD.encode(&blob), FileFormat::from_bytes(&blob).media_type().to_string())),
+      Ok((blob, media_type)) => Ok((general_purpose::STANDARD.encode(&blob), media_type)),
       Err(err) => Err(format!("Error decrypting pad to blob: {}", err))
     }
   } else {
@@ -159,6 +300,18 @@ async fn get_node_tree(
   }
 }
 
+#[tauri::command]
+async fn get_quarantined_pads(
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<Vec<QuarantinedPad>, String> {
+  let cipherpad = state.inner().lock().await;
+  if cipherpad.master_key.is_some() {
+    Ok(cipherpad.quarantined_pads.clone())
+  } else {
+    Err("No password".to_string())
+  }
+}
+
 #[tauri::command]
 async fn get_pad_map(
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
@@ -179,43 +332,213 @@ async fn get_pad_map(
 async fn decrypt_pad(
   id: Uuid,
   state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
-) -> Result<String, String> {
+) -> Result<DecryptedPad, String> {
   let cipherpad = state.inner().lock().await;
   if let Some(pool) = &cipherpad.pool {
     if let Some(master_key) = &cipherpad.master_key {
       if let Some(encrypted_pad) = cipherpad.pad_map.pads.get(&id) {
-        match encrypted_pad.clone().decrypt_pad_data(master_key, pool).await {
-          Ok(pad_data) => Ok(pad_data),
+        match encrypted_pad.clone().decrypt_pad(master_key, pool).await {
+          Ok(decrypted_pad) => Ok(decrypted_pad),
           Err(err) => Err(format!("Error decrypting pad: {}", err))
         }
       } else {
-        Err(format!("No pad with that id"))
+        Err("No pad with that id".to_string())
       }
     } else {
-      Err(format!("No password"))
+      Err("No password".to_string())
     }
   } else {
-    Err(format!("No connection"))
+    Err("No connection".to_string())
   }
 }
 
 #[tauri::command]
-async fn delete_pad(
+async fn get_pad_checksum(
   id: Uuid,
-  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
-) -> Result<(), String> {
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<String, String> {
   let cipherpad = state.inner().lock().await;
-  if let (Some(pool), Some(_)) = (&cipherpad.pool, &cipherpad.master_key) {
-    if let Some(encrypted_pad) = cipherpad.pad_map.pads.clone().get(&id) {
-      match encrypted_pad.clone().delete_node(pool).await {
-        Ok(_) => Ok(()),
-        Err(err) => Err(format!("Error deleting pad: {}", err))
+  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
+    if let Some(encrypted_pad) = cipherpad.pad_map.pads.get(&id) {
+      match encrypted_pad.clone().get_checksum(pool, master_key).await {
+        Ok(checksum) => Ok(checksum),
+        Err(err) => Err(format!("Error getting pad checksum: {}", err))
       }
     } else {
-      Err(format!("No pad with that id"))
+      Err("No pad with that id".to_string())
     }
   } else {
-    Err(format!("No connection"))
+    Err("No connection and/or authentication".to_string())
+  }
+}
+
+#[tauri::command]
+async fn get_pad_thumbnail(
+  id: Uuid,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<Option<String>, String> {
+  let cipherpad = state.inner().lock().await;
+  if let (Some(pool), Some(master_key)) = (&cipherpad.pool, &cipherpad.master_key) {
+    if let Some(encrypted_pad) = cipherpad.pad_map.pads.get(&id) {
+      match encrypted_pad.clone().get_thumbnail(pool, master_key).await {
+        Ok(thumbnail) => Ok(thumbnail.map(|thumbnail| general_purpose::STANDARD.encode(&thumbnail))),
+        Err(err) => Err(format!("Error getting pad thumbnail: {}", err))
+      }
+    } else {
+      Err("No pad with that id".to_string())
+    }
+  } else {
+    Err("No connection and/or authentication".to_string())
+  }
+}
+
+#[tauri::command]
+async fn get_vault_settings(
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<VaultSettings, String> {
+  let cipherpad = state.inner().lock().await;
+  if let (Some(pool), Some(_)) = (&cipherpad.pool, &cipherpad.master_key) {
+    match VaultSettings::load(pool).await {
+      Ok(settings) => Ok(settings),
+      Err(err) => Err(format!("Error loading vault settings: {}", err))
+    }
+  } else {
+    Err("No connection and/or authentication".to_string())
+  }
+}
+
+#[tauri::command]
+async fn set_vault_settings(
+  settings: VaultSettings,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let cipherpad = state.inner().lock().await;
+  if let (Some(pool), Some(_)) = (&cipherpad.pool, &cipherpad.master_key) {
+    match settings.save(pool).await {
+      Ok(_) => Ok(()),
+      Err(err) => Err(format!("Error saving vault settings: {}", err))
+    }
+  } else {
+    Err("No connection and/or authentication".to_string())
+  }
+}
+
+#[tauri::command]
+async fn list_trash(
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<Vec<TrashedPad>, String> {
+  let cipherpad = state.inner().lock().await;
+  match cipherpad.list_trash().await {
+    Ok(trashed_pads) => Ok(trashed_pads),
+    Err(err) => Err(format!("Error listing trash: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn restore_pad(
+  id: Uuid,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.restore_pad(id).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error restoring pad: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn empty_trash(
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.empty_trash().await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error emptying trash: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn compact_vault(
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let cipherpad = state.inner().lock().await;
+  match cipherpad.compact_vault().await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error compacting vault: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn move_pad(
+  id: Uuid,
+  new_parent_id: Option<Uuid>,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.move_pads(vec![id], new_parent_id).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error moving pad: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn move_pads(
+  ids: Vec<Uuid>,
+  new_parent_id: Option<Uuid>,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.move_pads(ids, new_parent_id).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error moving pads: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn reorder_pad(
+  id: Uuid,
+  position: usize,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.reorder_pad(id, position).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error reordering pad: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn create_backup(
+  directory: Option<String>,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>
+) -> Result<BackupReport, String> {
+  let pool = {
+    let cipherpad = state.inner().lock().await;
+    match (&cipherpad.pool, &cipherpad.master_key) {
+      (Some(pool), Some(_)) => pool.clone(),
+      _ => return Err("No connection and/or authentication".to_string())
+    }
+  };
+  let settings = VaultSettings::load(&pool).await.map_err(|err| format!("Error loading vault settings: {}", err))?;
+  let directory = match directory.or(settings.backup_directory) {
+    Some(directory) => directory,
+    None => return Err("No backup directory configured".to_string())
+  };
+  match cipherpad::create_backup(pool, &directory, settings.backup_keep_daily, settings.backup_keep_weekly).await {
+    Ok(report) => Ok(report),
+    Err(err) => Err(format!("Error creating backup: {}", err))
+  }
+}
+
+#[tauri::command]
+async fn delete_pad(
+  id: Uuid,
+  state: tauri::State<'_, Arc<Mutex<Cipherpad>>>,
+) -> Result<(), String> {
+  let mut cipherpad = state.inner().lock().await;
+  match cipherpad.delete_pad(id).await {
+    Ok(_) => Ok(()),
+    Err(err) => Err(format!("Error deleting pad: {}", err))
   }
 }
 
@@ -227,7 +550,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   tauri::Builder::default()
     .manage(cipherpad)
-    .invoke_handler(tauri::generate_handler![open_or_create_cipherpad, unlock_cipherpad, get_node_tree, get_pad_map, create_pad, update_pad, delete_pad, encrypt_file_to_pad, decrypt_pad_to_file, decrypt_pad_to_blob, decrypt_pad])
+    .setup(|app| {
+      let cipherpad = app.state::<Arc<Mutex<Cipherpad>>>().inner().clone();
+      let app_handle = app.handle();
+      tauri::async_runtime::spawn(cipherpad::run_scheduled_backups(cipherpad, move |result| {
+        let _ = match result {
+          Ok(report) => app_handle.emit_all("backup-created", report),
+          Err(err) => app_handle.emit_all("backup-failed", format!("Error creating backup: {}", err))
+        };
+      }));
+      Ok(())
+    })
+    .register_uri_scheme_protocol("cipherpad", protocol::handle_pad_request)
+    .invoke_handler(tauri::generate_handler![open_or_create_cipherpad, unlock_cipherpad, get_node_tree, get_pad_map, create_pad, update_pad, delete_pad, encrypt_file_to_pad, decrypt_pad_to_file, import_directory, export_subtree, export_archive, import_archive, decrypt_pad_to_blob, decrypt_pad, cancel_job, get_pad_checksum, get_pad_thumbnail, get_vault_settings, set_vault_settings, create_backup, move_pad, move_pads, reorder_pad, get_quarantined_pads, verify_vault, list_trash, restore_pad, empty_trash, compact_vault])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 
@@ -0,0 +1,142 @@
+use std::{sync::Arc, io::{Read, Seek, SeekFrom}};
+
+use anyhow::bail;
+use file_format::FileFormat;
+use tauri::{AppHandle, Manager, async_runtime::Mutex, http::{Request, Response, ResponseBuilder, HttpRange, header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE}, status::StatusCode}};
+use uuid::Uuid;
+
+use crate::cipherpad::{Cipherpad, MEDIA_SNIFF_SIZE};
+
+const MAX_RANGE_LEN: u64 = 1024 * 1024; // Most bytes decrypted and sent back for a single request
+
+enum PadRange {
+  Full,
+  Partial(u64, u64),
+  NotSatisfiable
+}
+
+struct PadContent {
+  len: u64,
+  mime_type: String,
+  range: PadRange,
+  body: Vec<u8>
+}
+
+// Accepts cipherpad://pad/<uuid> as well as the https://cipherpad.localhost/pad%2F<uuid> form used on Windows
+fn parse_pad_id(uri: &str) -> Option<Uuid> {
+  let uri = uri.replace("%2F", "/").replace("%2f", "/");
+  let path = uri.split(['?', '#']).next()?;
+  let id = path.trim_end_matches('/').rsplit('/').next()?;
+  Uuid::parse_str(id).ok()
+}
+
+async fn read_pad_content(cipherpad: Arc<Mutex<Cipherpad>>, id: Uuid, range_header: Option<String>) -> Result<PadContent, anyhow::Error> {
+  // Only holds the lock long enough to look the pad up, so decrypting media does not hold up commands
+  let (pool, master_key, encrypted_pad) = {
+    let cipherpad = cipherpad.lock().await;
+    match (&cipherpad.pool, &cipherpad.master_key) {
+      (Some(pool), Some(master_key)) => match cipherpad.pad_map.pads.get(&id) {
+        Some(encrypted_pad) => (pool.clone(), *master_key, encrypted_pad.clone()),
+        None => bail!("No pad with that id")
+      },
+      _ => bail!("No connection and/or authentication")
+    }
+  };
+  let media_type = encrypted_pad.clone().get_media_type()?;
+  encrypted_pad.read_pad_data(&pool, &master_key, move |mut pad_reader| {
+    let len = pad_reader.len();
+    let mime_type = match media_type {
+      Some(media_type) => media_type,
+      None => {
+        let mut head = Vec::new();
+        pad_reader.set_read_end(MEDIA_SNIFF_SIZE);
+        (&mut pad_reader).take(MEDIA_SNIFF_SIZE).read_to_end(&mut head)?;
+        FileFormat::from_bytes(&head).media_type().to_string()
+      }
+    };
+
+    let range = match range_header {
+      Some(range_header) => match HttpRange::parse(&range_header, len) {
+        Ok(ranges) => match ranges.first() {
+          Some(range) if range.length > 0 => {
+            PadRange::Partial(range.start, range.start + range.length.min(MAX_RANGE_LEN) - 1)
+          },
+          _ => PadRange::NotSatisfiable
+        },
+        Err(_) => PadRange::NotSatisfiable
+      },
+      // Larger pads are never read whole, players follow up with range requests for the rest
+      None if len > MAX_RANGE_LEN => PadRange::Partial(0, MAX_RANGE_LEN - 1),
+      None => PadRange::Full
+    };
+
+    let body = match range {
+      PadRange::Partial(start, end) => {
+        let mut body = Vec::with_capacity((end + 1 - start) as usize);
+        pad_reader.set_read_end(end + 1);
+        pad_reader.seek(SeekFrom::Start(start))?;
+        (&mut pad_reader).take(end + 1 - start).read_to_end(&mut body)?;
+        body
+      },
+      PadRange::Full => {
+        let mut body = Vec::with_capacity(len as usize);
+        pad_reader.set_read_end(len);
+        pad_reader.seek(SeekFrom::Start(0))?;
+        pad_reader.read_to_end(&mut body)?;
+        body
+      },
+      PadRange::NotSatisfiable => Vec::new()
+    };
+
+    Ok(PadContent {
+      len,
+      mime_type,
+      range,
+      body
+    })
+  }).await
+}
+
+pub fn handle_pad_request(app: &AppHandle, request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+  let id = match parse_pad_id(request.uri()) {
+    Some(id) => id,
+    None => return ResponseBuilder::new().status(StatusCode::NOT_FOUND).body(Vec::new())
+  };
+  let range_header = request.headers()
+    .get("range")
+    .and_then(|range| range.to_str().ok())
+    .map(|range| range.to_string());
+  let cipherpad = app.state::<Arc<Mutex<Cipherpad>>>().inner().clone();
+
+  // Protocol requests are handled synchronously on the main thread, which is already inside the tokio runtime.
+  // The main thread only blocks while at most MAX_RANGE_LEN bytes and the chunks covering them are decrypted.
+  let content = std::thread::spawn(move || {
+    tauri::async_runtime::block_on(read_pad_content(cipherpad, id, range_header))
+  }).join().map_err(|_| "Pad reading thread panicked")?;
+
+  match content {
+    Ok(content) => {
+      let response = ResponseBuilder::new()
+        .mimetype(&content.mime_type)
+        .header(ACCEPT_RANGES, "bytes");
+      match content.range {
+        PadRange::Full => response
+          .status(StatusCode::OK)
+          .header(CONTENT_LENGTH, content.len)
+          .body(content.body),
+        PadRange::Partial(start, end) => response
+          .status(StatusCode::PARTIAL_CONTENT)
+          .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, content.len))
+          .header(CONTENT_LENGTH, end + 1 - start)
+          .body(content.body),
+        PadRange::NotSatisfiable => response
+          .status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .header(CONTENT_RANGE, format!("bytes */{}", content.len))
+          .body(content.body)
+      }
+    },
+    Err(err) => ResponseBuilder::new()
+      .status(StatusCode::INTERNAL_SERVER_ERROR)
+      .body(format!("Error reading pad: {}", err).into_bytes())
+  }
+}
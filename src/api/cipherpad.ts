@@ -1,5 +1,6 @@
 import { invoke } from '@tauri-apps/api';
 import { NodeTree } from '../types/pad';
+import { BackupReport, VaultSettings } from '../types/cipherpad';
 
 export async function openOrCreateCipherpad(path: string) {
   await invoke('open_or_create_cipherpad', {path});
@@ -11,4 +12,20 @@ export async function unlockCipherpad(password: string) {
 
 export async function getNodeTree() {
   return await invoke('get_node_tree') as NodeTree;
+}
+
+export async function compactVault() {
+  await invoke('compact_vault');
+}
+
+export async function getVaultSettings() {
+  return await invoke('get_vault_settings') as VaultSettings;
+}
+
+export async function setVaultSettings(settings: VaultSettings) {
+  await invoke('set_vault_settings', {settings});
+}
+
+export async function createBackup(directory?: string) {
+  return await invoke('create_backup', {directory}) as BackupReport;
 }
\ No newline at end of file
@@ -0,0 +1,20 @@
+import { invoke } from '@tauri-apps/api';
+import { listen } from '@tauri-apps/api/event';
+import { JobProgress } from '../types/job';
+
+export async function runJob<T>(jobId: string, start: () => Promise<T>, onProgress?: (progress: JobProgress) => void): Promise<T> {
+  const unlisten = await listen<JobProgress>('job-progress', ({payload}) => {
+    if (payload.id === jobId && onProgress !== undefined) {
+      onProgress(payload);
+    }
+  });
+  try {
+    return await start();
+  } finally {
+    unlisten();
+  }
+}
+
+export async function cancelJob(jobId: string) {
+  await invoke('cancel_job', {jobId});
+}
@@ -1,6 +1,17 @@
 import { invoke } from '@tauri-apps/api';
-import { EncryptedPad, Pad, PadData, PadMap, PadMetadata, PadNode, SerializedPadMap } from '../types/pad';
+import { convertFileSrc } from '@tauri-apps/api/tauri';
+import { ArchiveReport, DecryptedPad, EncryptedPad, ExportReport, ImportReport, Pad, PadData, PadMap, PadMetadata, PadNode, QuarantinedPad, SerializedPadMap, SerializedTrashedPad, TrashedPad, VerifyReport } from '../types/pad';
+import { JobProgress } from '../types/job';
 import { serializeEncryptedPad, serializePad, serializePadNode } from '../utils/pad-utils';
+import { runJob } from './job';
+
+export async function verifyVault(onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID()) {
+  return await runJob(jobId, () => invoke('verify_vault', {jobId}) as Promise<VerifyReport>, onProgress);
+}
+
+export async function getQuarantinedPads() {
+  return await invoke('get_quarantined_pads') as QuarantinedPad[];
+}
 
 export async function getPadMap(): Promise<PadMap> {
   const serializedPadMap = await invoke('get_pad_map') as SerializedPadMap;
@@ -20,14 +31,32 @@ export async function createPad(pad: Pad) {
   return await invoke('create_pad', {pad: serializedPad}) as string;
 }
 
-export async function encryptFileToPad(encryptedPad: EncryptedPad, file: string) {
+// Resolves to the names of the metadata fields stripped from the file, the vault default is used when stripMetadata is omitted
+export async function encryptFileToPad(encryptedPad: EncryptedPad, file: string, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID(), stripMetadata?: boolean) {
   const serializedEncryptedPad = serializeEncryptedPad(encryptedPad);
-  return await invoke('encrypt_file_to_pad', {encryptedPad: serializedEncryptedPad, file});
+  return await runJob(jobId, () => invoke('encrypt_file_to_pad', {encryptedPad: serializedEncryptedPad, file, options: {stripMetadata}, jobId}) as Promise<string[]>, onProgress);
 }
 
-export async function decrpytPadToFile(encryptedPad: EncryptedPad, file: string) {
+export async function decrpytPadToFile(encryptedPad: EncryptedPad, file: string, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID()) {
   const serializedEncryptedPad = serializeEncryptedPad(encryptedPad);
-  return await invoke('decrypt_pad_to_file', {encryptedPad: serializedEncryptedPad, file});
+  return await runJob(jobId, () => invoke('decrypt_pad_to_file', {encryptedPad: serializedEncryptedPad, file, jobId}), onProgress);
+}
+
+export async function importDirectory(directory: string, parentId: string | null, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID(), stripMetadata?: boolean) {
+  return await runJob(jobId, () => invoke('import_directory', {directory, parentId, stripMetadata, jobId}) as Promise<ImportReport>, onProgress);
+}
+
+export async function exportSubtree(id: string, directory: string, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID()) {
+  return await runJob(jobId, () => invoke('export_subtree', {id, directory, jobId}) as Promise<ExportReport>, onProgress);
+}
+
+// Exports the whole vault when id is null
+export async function exportArchive(id: string | null, file: string, passphrase: string, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID()) {
+  return await runJob(jobId, () => invoke('export_archive', {id, file, passphrase, jobId}) as Promise<ArchiveReport>, onProgress);
+}
+
+export async function importArchive(file: string, passphrase: string, parentId: string | null, preserveIds: boolean = false, onProgress?: (progress: JobProgress) => void, jobId: string = crypto.randomUUID()) {
+  return await runJob(jobId, () => invoke('import_archive', {file, passphrase, parentId, preserveIds, jobId}) as Promise<ArchiveReport>, onProgress);
 }
 
 export async function decrpytPadToBlob(encryptedPad: EncryptedPad): Promise<{blob: Blob, mime: string}> {
@@ -42,16 +71,55 @@ export async function decrpytPadToBlob(encryptedPad: EncryptedPad): Promise<{blo
   return {blob: new Blob([uInt8Array]), mime: blobMime};
 }
 
+export function getPadUrl(id: string) {
+  return convertFileSrc(`pad/${id}`, 'cipherpad');
+}
+
+export async function getPadChecksum(id: string) {
+  return await invoke('get_pad_checksum', {id}) as string;
+}
+
+export async function getPadThumbnail(id: string): Promise<string | null> {
+  const thumbnailBase64 = await invoke('get_pad_thumbnail', {id}) as string | null;
+  return thumbnailBase64 === null ? null : `data:image/jpeg;base64,${thumbnailBase64}`;
+}
+
 export async function deletePadById(id: string) {
   return await invoke('delete_pad', {id});
 }
 
+export async function listTrash(): Promise<TrashedPad[]> {
+  const serializedTrashedPads = await invoke('list_trash') as SerializedTrashedPad[];
+  return serializedTrashedPads.map(trashedPad => ({...trashedPad, metadata: JSON.parse(trashedPad.metadata) as PadMetadata}));
+}
+
+export async function restorePad(id: string) {
+  await invoke('restore_pad', {id});
+}
+
+export async function emptyTrash() {
+  await invoke('empty_trash');
+}
+
+export async function movePad(id: string, newParentId: string | null) {
+  await invoke('move_pad', {id, newParentId});
+}
+
+export async function movePads(ids: string[], newParentId: string | null) {
+  await invoke('move_pads', {ids, newParentId});
+}
+
+export async function reorderPad(id: string, position: number) {
+  await invoke('reorder_pad', {id, position});
+}
+
+// Resolves to the new revision, a stale revision rejects with a SaveError carrying the current one
 export async function updatePad(padNode: PadNode) {
   const serializedPadNode = serializePadNode(padNode);
-  await invoke('update_pad', {padNode: serializedPadNode});
+  return await invoke('update_pad', {padNode: serializedPadNode}) as number;
 }
 
-export async function decryptPad(id: string): Promise<PadData> {
-  const decryptedPadData = await invoke('decrypt_pad', {id}) as string;
-  return JSON.parse(decryptedPadData) as PadData;
+export async function decryptPad(id: string): Promise<DecryptedPad<PadData>> {
+  const {padData, revision} = await invoke('decrypt_pad', {id}) as {padData: string, revision: number};
+  return {padData: JSON.parse(padData) as PadData, revision};
 }
\ No newline at end of file
@@ -135,7 +135,7 @@ export default function CipherpadProvider({children}: PropsWithChildren) {
       setCipherpadUiState(cipherpadUiState => ({
         ...cipherpadUiState,
         parentNode: padMap.pads[currentNode].parentId,
-        currentNodeChildren: newCurrentPageChildren.sort((a, b) => a.metadata.name.toLowerCase().localeCompare(b.metadata.name.toLowerCase())),
+        currentNodeChildren: newCurrentPageChildren,
         loading: false
       }));
     }
@@ -147,7 +147,7 @@ export default function CipherpadProvider({children}: PropsWithChildren) {
       setCipherpadUiState(cipherpadUiState => ({
         ...cipherpadUiState,
         parentNode: null,
-        currentNodeChildren: newCurrentPageChildren.sort((a, b) => a.metadata.name.toLowerCase().localeCompare(b.metadata.name.toLowerCase())),
+        currentNodeChildren: newCurrentPageChildren,
         loading: false
       }));
     }
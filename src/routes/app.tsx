@@ -6,7 +6,7 @@ import Button from "react-bootstrap/Button";
 import Container from "react-bootstrap/Container";
 import Modal from "react-bootstrap/Modal";
 import Table from "react-bootstrap/Table";
-import { createPad, decrpytPadToFile, deletePadById, encryptFileToPad } from "../api/pad";
+import { createPad, decrpytPadToFile, deletePadById, encryptFileToPad, exportSubtree, importDirectory } from "../api/pad";
 import { EncryptedPad, Pad } from '../types/pad';
 
 export default function App() {
@@ -14,6 +14,7 @@ export default function App() {
   const [deleteId, setDeleteId] = useState<string | undefined>(undefined);
   const [uploadingId, setUploadingId] = useState<string | undefined>(undefined);
   const [lastError, setLastError] = useState<string | undefined>(undefined);
+  const [notice, setNotice] = useState<string | undefined>(undefined);
   const [selectedBlobPad, setSelectedBlobPad] = useState<EncryptedPad | undefined>(undefined);
   const navigate = useNavigate();
 
@@ -30,21 +31,25 @@ export default function App() {
         if (fileName !== undefined) {
           const newBlobPad: Pad = {
             parentId: parentNode,
-            padMetadata: {type: 'blob', name: fileName, fileName, encryptedDataOffset: 0, createdAt: Date.now(), lastModifiedAt: Date.now()},
+            padMetadata: {type: 'blob', name: fileName, fileName, createdAt: Date.now(), lastModifiedAt: Date.now()},
             padData: ''
           };
     
           const id = await createPad(newBlobPad);
           setUploadingId(id);
+          let strippedFields: string[];
           try {
             await refreshCipherpadData();
-            await encryptFileToPad({id, parentId: newBlobPad.parentId, metadata: newBlobPad.padMetadata}, fileToUpload);
+            strippedFields = await encryptFileToPad({id, parentId: newBlobPad.parentId, metadata: newBlobPad.padMetadata, revision: 0}, fileToUpload);
           }
           catch (e) {
             await deletePadById(id);
             throw e;
           }
           await refreshCipherpadData();
+          if (strippedFields.length > 0) {
+            setNotice(`Removed metadata from ${fileName}: ${strippedFields.join(', ')}`);
+          }
         }
       }
     }
@@ -54,6 +59,37 @@ export default function App() {
     setUploadingId(undefined);
   }
 
+  const onImportFolderButtonClicked = async () => {
+    try {
+      const directoryToImport = await open({directory: true});
+      if (directoryToImport !== null && !Array.isArray(directoryToImport)) {
+        const report = await importDirectory(directoryToImport, parentNode);
+        await refreshCipherpadData();
+        if (report.failures.length > 0) {
+          setLastError(`Imported ${report.importedFiles} files, ${report.failures.length} failed: ${report.failures.map(({path, error}) => `${path} (${error})`).join(', ')}`);
+        }
+      }
+    }
+    catch (e) {
+      setLastError(e instanceof Error ? e.message : String(e));
+    }
+  }
+
+  const exportPad = async (pad: EncryptedPad) => {
+    try {
+      const exportDirectory = await open({directory: true});
+      if (exportDirectory !== null && !Array.isArray(exportDirectory)) {
+        const report = await exportSubtree(pad.id, exportDirectory);
+        if (report.failures.length > 0) {
+          setLastError(`Exported ${report.exportedPads} pads, ${report.failures.length} failed: ${report.failures.map(({name, error}) => `${name} (${error})`).join(', ')}`);
+        }
+      }
+    }
+    catch (e) {
+      setLastError(e instanceof Error ? e.message : String(e));
+    }
+  }
+
   const downloadPadToFile = async (pad: EncryptedPad) => {
     try {
       if (pad.metadata.type == 'blob') {
@@ -101,6 +137,7 @@ export default function App() {
       <h1>Cipherpad</h1>
       <Button variant="secondary" role="link" size="sm" onClick={onCreateButtonClicked}>Create</Button>{' '}
       <Button variant="secondary" role="link" size="sm" onClick={onUploadButtonClicked}>Upload</Button>{' '}
+      <Button variant="secondary" role="link" size="sm" onClick={onImportFolderButtonClicked}>Import folder</Button>{' '}
       {currentNode !== null && <Button variant="secondary" role="link" size="sm" onClick={() => {setCurrentNode(parentNode)}}>Up</Button>}
       <Table bordered size="sm">
         <tbody>
@@ -128,6 +165,11 @@ export default function App() {
                   /
                 </Button>
               </td>
+              <td className="text-center">
+                <Button variant="secondary" onClick={() => exportPad(encryptedPad)}>
+                  Export
+                </Button>
+              </td>
               <td className="text-center">
                 <Button variant="secondary" onClick={() => {
                   setDeleteId(encryptedPad.id);
@@ -170,6 +212,17 @@ export default function App() {
           </Button>
         </Modal.Footer>
       </Modal>
+      <Modal show={notice !== undefined} onHide={() => {setNotice(undefined)}}>
+        <Modal.Header closeButton>
+          <Modal.Title>Upload complete</Modal.Title>
+        </Modal.Header>
+        <Modal.Body>{notice}</Modal.Body>
+        <Modal.Footer>
+          <Button variant="primary" onClick={() => setNotice(undefined)}>
+            OK
+          </Button>
+        </Modal.Footer>
+      </Modal>
       <Modal show={lastError !== undefined} onHide={() => {setLastError(undefined)}}>
         <Modal.Header closeButton>
           <Modal.Title>Error</Modal.Title>
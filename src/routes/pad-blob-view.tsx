@@ -1,11 +1,11 @@
 import { useNavigate } from "react-router-dom";
 import { useCipherpad } from "../providers/CipherpadProvider";
 import { useEffect, useState } from "react";
-import { decrpytPadToBlob } from "../api/pad";
+import { decrpytPadToBlob, getPadUrl } from "../api/pad";
 
 export interface PadBlobViewState {
   pad: {
-    blob: Blob,
+    src: string,
     mime: string
   } | undefined
 }
@@ -17,20 +17,29 @@ export default function PadBlobView() {
   const { cipherpadUiState: {currentPad} } = cipherpadContext;
 
   const loadBlob = async () => {
-    if (currentPad !== null) {
-      const decryptedBlob = await decrpytPadToBlob(currentPad);
-      setPadViewState({pad: decryptedBlob});
-      console.log(decryptedBlob);
+    if (currentPad !== null && currentPad.metadata.type == 'blob') {
+      // Video and audio are streamed in ranges without decrypting them up front, images are small enough to load whole
+      const mediaType = currentPad.metadata.mediaType;
+      if (mediaType !== undefined && /^(video|audio)/.test(mediaType)) {
+        setPadViewState({pad: {src: getPadUrl(currentPad.id), mime: mediaType}});
+      }
+      else {
+        const decryptedBlob = await decrpytPadToBlob(currentPad);
+        setPadViewState({pad: {src: URL.createObjectURL(decryptedBlob.blob), mime: decryptedBlob.mime}});
+      }
     }
   };
 
   const getViewer = () => {
     if (pad === undefined) return <p>Decrypting...</p>;
     if (/^image/g.test(pad.mime)) {
-      return <img src={URL.createObjectURL(pad.blob)} alt={currentPad?.metadata.name} />
+      return <img src={pad.src} alt={currentPad?.metadata.name} />
     }
     else if (/^video/g.test(pad.mime)) {
-      return <video src={URL.createObjectURL(pad.blob)} />
+      return <video src={pad.src} controls />
+    }
+    else if (/^audio/g.test(pad.mime)) {
+      return <audio src={pad.src} controls />
     }
     else {
       return <p>Unable to view. Unsupported type.</p>
@@ -5,7 +5,7 @@ import { updatePad } from "../api/pad";
 import { useCipherpad } from "../providers/CipherpadProvider";
 import { useNavigate } from "react-router-dom";
 import { createNewPad, decryptTextPad } from "../utils/pad-utils";
-import { TextPadData, TextPadMetadata } from "../types/pad";
+import { SaveError, TextPadData, TextPadMetadata } from "../types/pad";
 
 export interface PadEditState {
   metadata: TextPadMetadata,
@@ -15,7 +15,8 @@ export interface PadEditState {
   rendered: string,
   historyStack: string[],
   isNewPad: boolean,
-  isDirty: boolean
+  isDirty: boolean,
+  revision: number
 }
 
 export default function PadEdit() {
@@ -41,9 +42,10 @@ export default function PadEdit() {
     rendered: '',
     historyStack: [],
     isNewPad: false,
-    isDirty: false
+    isDirty: false,
+    revision: 0
   })
-  const { metadata, data, showRendered, rendered, historyStack, isDirty } = padEditState;
+  const { metadata, data, showRendered, rendered, historyStack, isDirty, revision } = padEditState;
   const { name } = metadata;
   const { text } = data;
 
@@ -68,6 +70,13 @@ export default function PadEdit() {
     }));
   }
 
+  const setRevision = (revision: number) => {
+    setPadEditState(padEditState => ({
+      ...padEditState,
+      revision
+    }));
+  }
+
   const setName = (name: string) => {
     setMetadata({
       ...metadata,
@@ -133,9 +142,10 @@ export default function PadEdit() {
   const refreshNote = async() => {
     if (currentPad !== null && currentPad.metadata.type == 'text') {
       setMetadata(currentPad.metadata);
-      const textPadData = await decryptTextPad(currentPad.id);
+      const { padData: textPadData, revision } = await decryptTextPad(currentPad.id);
       setCleanData(textPadData);
       setData(textPadData);
+      setRevision(revision);
     }
     else {
       setName('');
@@ -156,7 +166,16 @@ export default function PadEdit() {
       await createNewPad({padMetadata: {...metadata, createdAt: Date.now(), lastModifiedAt: Date.now()}, padData: data, parentId: currentNode}, cipherpadContext);
     }
     else {
-      await updatePad({id: currentPad.id, pad: {padMetadata: {...metadata, lastModifiedAt: Date.now()}, padData: data, parentId: currentNode}});
+      try {
+        setRevision(await updatePad({id: currentPad.id, revision, pad: {padMetadata: {...metadata, lastModifiedAt: Date.now()}, padData: data, parentId: currentNode}}));
+      }
+      catch (e) {
+        const { message, currentRevision } = e as SaveError;
+        if (currentRevision !== undefined) {
+          throw new Error(`${message}. Reload the pad to see the newer changes before saving.`);
+        }
+        throw new Error(message);
+      }
     }
     setIsDirty(false);
   }
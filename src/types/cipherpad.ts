@@ -0,0 +1,13 @@
+export interface VaultSettings {
+  stripImageMetadata: boolean,
+  backupDirectory?: string | null,
+  backupIntervalHours?: number | null,
+  backupKeepDaily: number,
+  backupKeepWeekly: number,
+  trashRetentionDays?: number | null
+}
+
+export interface BackupReport {
+  path: string,
+  removedBackups: string[]
+}
\ No newline at end of file
@@ -0,0 +1,6 @@
+export interface JobProgress {
+  id: string,
+  bytesDone: number,
+  totalBytes: number,
+  currentItem?: string
+}
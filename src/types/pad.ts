@@ -8,6 +8,8 @@ export interface TextPadData {
 
 export type BlobPadData = '';
 
+export type MediaKind = 'image' | 'audio' | 'video' | 'document' | 'archive' | 'other';
+
 interface BasePadMetadata {
   type: 'text' | 'blob';
   name: string,
@@ -22,7 +24,14 @@ export interface TextPadMetadata extends BasePadMetadata {
 export interface BlobPadMetadata extends BasePadMetadata {
   type: 'blob',
   fileName: string,
-  encryptedDataOffset: number
+  encryptedDataOffset?: number,
+  sha256?: string,
+  originalSize?: number,
+  sourceModifiedAt?: number,
+  mediaType?: string,
+  extension?: string,
+  kind?: MediaKind,
+  strippedMetadata?: string[]
 }
 
 export type PadMetadata = TextPadMetadata | BlobPadMetadata;
@@ -43,12 +52,24 @@ export interface SerializedPad {
 
 export interface PadNode {
   id: string,
-  pad: Pad
+  pad: Pad,
+  revision: number
 }
 
 export interface SerializedPadNode {
   id: string,
-  pad: SerializedPad
+  pad: SerializedPad,
+  revision: number
+}
+
+export interface DecryptedPad<T extends PadData> {
+  padData: T,
+  revision: number
+}
+
+export interface SaveError {
+  message: string,
+  currentRevision?: number
 }
 
 export interface Node {
@@ -59,13 +80,31 @@ export interface Node {
 export interface SerializedEncryptedPad {
   id: string,
   parentId: string | null,
-  metadata: string
+  metadata: string,
+  sortKey?: string,
+  revision: number
+}
+
+export interface SerializedTrashedPad {
+  id: string,
+  parentId: string | null,
+  metadata: string,
+  deletedAt: number
+}
+
+export interface TrashedPad {
+  id: string,
+  parentId: string | null,
+  metadata: PadMetadata,
+  deletedAt: number
 }
 
 export interface EncryptedPad {
   id: string,
   parentId: string | null,
-  metadata: PadMetadata
+  metadata: PadMetadata,
+  sortKey?: string,
+  revision: number
 }
 
 export interface NodeTree {
@@ -82,4 +121,53 @@ export interface PadMap {
   pads: {
     [key: string]: EncryptedPad
   }
+}
+
+export interface ImportFailure {
+  path: string,
+  error: string
+}
+
+export interface ImportReport {
+  rootId: string,
+  importedFolders: number,
+  importedFiles: number,
+  failures: ImportFailure[]
+}
+
+export interface ExportFailure {
+  id: string,
+  name: string,
+  error: string
+}
+
+export interface ExportReport {
+  exportedPads: number,
+  failures: ExportFailure[]
+}
+
+export interface ArchiveReport {
+  createdAt: number,
+  pads: number,
+  rootIds: string[],
+  quarantinedPads: string[]
+}
+
+export interface QuarantinedPad {
+  id: string,
+  parentId: string | null,
+  error: string
+}
+
+export interface VerifyFailure {
+  id: string,
+  error: string
+}
+
+export interface VerifyReport {
+  checkedPads: number,
+  failures: VerifyFailure[],
+  orphans: string[],
+  cyclePads: string[],
+  integrityErrors: string[]
 }
\ No newline at end of file
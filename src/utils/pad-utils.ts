@@ -1,6 +1,6 @@
 import { createPad, decryptPad } from "../api/pad";
 import { CipherpadContextType } from "../providers/CipherpadProvider";
-import { EncryptedPad, Pad, PadMetadata, PadNode, SerializedEncryptedPad, SerializedPad, SerializedPadNode, TextPadData } from "../types/pad";
+import { DecryptedPad, EncryptedPad, Pad, PadMetadata, PadNode, SerializedEncryptedPad, SerializedPad, SerializedPadNode, TextPadData } from "../types/pad";
 
 
 export const createNewPad = async (pad: Pad, {refreshCipherpadData, setCurrentPad}: CipherpadContextType) => {
@@ -12,8 +12,8 @@ export const createNewPad = async (pad: Pad, {refreshCipherpadData, setCurrentPa
   }
 }
 
-export const decryptTextPad = async (id: string): Promise<TextPadData> => {
-  return await decryptPad(id) as TextPadData;
+export const decryptTextPad = async (id: string): Promise<DecryptedPad<TextPadData>> => {
+  return await decryptPad(id) as DecryptedPad<TextPadData>;
 }
 
 export const serializePad = (pad: Pad): SerializedPad => {